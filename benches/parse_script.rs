@@ -0,0 +1,43 @@
+// Times parsing scripts of doubling size, the time per byte should stay
+// roughly flat. Run with `cargo bench`.
+
+// See lib.rs
+#![allow(clippy::needless_return, clippy::redundant_static_lifetimes)]
+
+extern crate tclscan;
+
+use std::time::{Duration, Instant};
+use tclscan::rstcl;
+
+const PROC: &'static str = "proc p {a b} {
+    set x [list $a $b]
+    if {$x ne \"\"} {
+        puts \"x: $x [llength $x]\"
+    }
+}
+set y {a b c}
+";
+
+// Best of a few runs, to smooth out noise
+fn time<F: Fn() -> usize>(f: F) -> Duration {
+    return (0..5).map(|_| {
+        let start = Instant::now();
+        assert!(f() > 0);
+        start.elapsed()
+    }).min().unwrap();
+}
+
+fn bench(name: &str, make_script: &dyn Fn(usize) -> String) {
+    println!("{}", name);
+    for &n in [1000, 2000, 4000, 8000, 16000].iter() {
+        let script = make_script(n);
+        let elapsed = time(|| rstcl::iter_script(&script).filter(|parse| parse.is_ok()).count());
+        let per_byte = elapsed.as_secs_f64() * 1e9 / script.len() as f64;
+        println!("  {:>9} bytes {:>10.2?} {:>6.1} ns/byte", script.len(), elapsed, per_byte);
+    }
+}
+
+fn main() {
+    bench("many commands", &|n| PROC.repeat(n));
+    bench("one long line", &|n| format!("list {}\n", "$a [b] c ".repeat(n * 4)));
+}
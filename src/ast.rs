@@ -0,0 +1,201 @@
+//! A tree of a whole script. Arguments which commands like `if`, `while` and
+//! `proc` interpret as scripts or expressions (see `check_command`) are
+//! parsed too, as are command substitutions, so the tree can be walked
+//! without re-parsing anything.
+
+use rstcl::{self, ParseError, Position, Span, SubstFlags, TclParse, TclToken, TokenType};
+use {inner_start, is_braced, param_types, subst_flags, Code, ScanOptions};
+
+/// A parsed script, possibly the body of a command
+#[derive(Debug, PartialEq)]
+pub struct Script<'a> {
+    pub commands: Vec<Command<'a>>,
+    /// Parts of the script which couldn't be parsed, along with the text
+    /// skipped over (see `rstcl::parse_script_recover_at`)
+    pub errors: Vec<(ParseError, &'a str)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Command<'a> {
+    pub comment: Option<&'a str>,
+    pub command: Option<&'a str>,
+    pub words: Vec<Word<'a>>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Word<'a> {
+    pub token: TclToken<'a>,
+    /// The parsed contents, if this is a braced script or expr argument
+    pub body: Option<Body<'a>>,
+    /// The scripts of the command substitutions in this word, in order
+    pub substs: Vec<Script<'a>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Body<'a> {
+    Script(Script<'a>),
+    Expr(Expr<'a>),
+    /// The bodies in a list of patterns and bodies, e.g. of `switch`,
+    /// leaving out `-` (fall through to the next body)
+    Scripts(Vec<Script<'a>>),
+    /// The contents of a string which is substituted, e.g. by `subst`
+    Subst(Subst<'a>),
+    /// The contents of an expr or subst argument (or list of patterns and
+    /// bodies) couldn't be parsed
+    Error(ParseError),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Expr<'a> {
+    pub parse: TclParse<'a>,
+    /// The scripts of the command substitutions in this expr, in order
+    pub substs: Vec<Script<'a>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Subst<'a> {
+    pub parse: TclParse<'a>,
+    /// The scripts of the command substitutions in this string, in order
+    pub substs: Vec<Script<'a>>,
+}
+
+/// Takes: a string, which should be a tcl script
+/// Returns: the tree of the script and everything inside it
+///
+/// ```
+/// use tclscan::ast::{parse_script,Body};
+/// let script = parse_script("proc p {} {\n  if {$x} { eval [foo] }\n}");
+/// let proc_body = match script.commands[0].words[3].body {
+///     Some(Body::Script(ref body)) => body,
+///     _ => panic!(),
+/// };
+/// let if_cmd = &proc_body.commands[0];
+/// assert!(if_cmd.words[0].token.val == "if");
+/// match if_cmd.words[1].body {
+///     Some(Body::Expr(ref expr)) => assert!(expr.parse.tokens[0].val == "$x"),
+///     _ => panic!(),
+/// }
+/// let eval_cmd = match if_cmd.words[2].body {
+///     Some(Body::Script(ref body)) => &body.commands[0],
+///     _ => panic!(),
+/// };
+/// let foo_cmd = &eval_cmd.words[1].substs[0].commands[0];
+/// assert!(foo_cmd.words[0].token.val == "foo" && foo_cmd.span.start.line == 2);
+/// let script = parse_script("subst {a $b [c $d]}");
+/// match script.commands[0].words[1].body {
+///     Some(Body::Subst(ref subst)) => assert!(subst.substs[0].commands[0].words[0].token.val == "c"),
+///     _ => panic!(),
+/// }
+/// let script = parse_script("switch $x {a - b {puts 1} c {puts 2}}");
+/// match script.commands[0].words[2].body {
+///     Some(Body::Scripts(ref bodies)) => {
+///         assert!(bodies.len() == 2 && bodies[1].commands[0].words[1].token.val == "2");
+///     },
+///     _ => panic!(),
+/// }
+/// ```
+pub fn parse_script<'a>(string: &'a str) -> Script<'a> {
+    return parse_script_with(string, &ScanOptions::default());
+}
+
+/// As `parse_script`, with the signatures in `options` deciding which
+/// arguments are bodies, as they do for `scan_script_with`
+///
+/// ```
+/// use tclscan::ScanOptions;
+/// use tclscan::ast::{parse_script_with,Body};
+/// let mut options = ScanOptions::default();
+/// options.signatures.insert("db::each", "normal block").unwrap();
+/// let script = parse_script_with("db::each $q {eval $x}", &options);
+/// match script.commands[0].words[2].body {
+///     Some(Body::Script(ref body)) => assert!(body.commands[0].words[0].token.val == "eval"),
+///     _ => panic!(),
+/// }
+/// ```
+pub fn parse_script_with<'a>(string: &'a str, options: &ScanOptions) -> Script<'a> {
+    return parse_script_at(string, Position::start(), options);
+}
+
+/// As `parse_script_with`, for a string starting at `pos` in the original
+/// source.
+pub fn parse_script_at<'a>(string: &'a str, pos: Position, options: &ScanOptions) -> Script<'a> {
+    let mut script = Script { commands: vec![], errors: vec![] };
+    for parsed in rstcl::parse_script_recover_at(string, pos) {
+        match parsed {
+            Ok(parse) => script.commands.push(build_command(parse, options)),
+            Err(err) => script.errors.push(err),
+        }
+    }
+    return script;
+}
+
+fn build_command<'a>(parse: TclParse<'a>, options: &ScanOptions) -> Command<'a> {
+    let param_types = match parse.tokens.len() {
+        0 => None,
+        _ => param_types(&parse.tokens, options),
+    }.unwrap_or(vec![]);
+    let flags = subst_flags(&parse.tokens);
+    let words = parse.tokens.into_iter().enumerate().map(|(i, token)| {
+        // The command name itself is never a body
+        let param_type = if i == 0 { None } else { param_types.get(i - 1) };
+        build_word(token, param_type, flags, options)
+    }).collect();
+    return Command { comment: parse.comment, command: parse.command, words: words, span: parse.span };
+}
+
+fn build_word<'a>(token: TclToken<'a>, param_type: Option<&Code>, flags: SubstFlags, options: &ScanOptions) -> Word<'a> {
+    let mut substs = vec![];
+    command_substs(&token, &mut substs, options);
+    let val = token.val;
+    // Only braced arguments can be parsed ahead of time, as in check_block
+    let body = if is_braced(&token) {
+        let contents = &val[1..val.len()-1];
+        match param_type {
+            Some(&Code::Block) | Some(&Code::Callback) => Some(Body::Script(parse_script_at(contents, inner_start(&token), options))),
+            Some(&Code::PatternBodies) => Some(match rstcl::parse_list_at(contents, inner_start(&token)) {
+                Ok(elements) => Body::Scripts(elements.chunks(2)
+                    .filter_map(|pair| pair.get(1))
+                    .filter(|body| body.value != "-")
+                    .map(|body| parse_script_at(body.val, body.span.start, options))
+                    .collect()),
+                Err(err) => Body::Error(err),
+            }),
+            Some(&Code::Subst) => Some(match rstcl::parse_subst_flags_at(contents, inner_start(&token), flags) {
+                Ok((parse, _)) => {
+                    let mut subst_substs = vec![];
+                    for tok in parse.tokens.iter() {
+                        command_substs(tok, &mut subst_substs, options);
+                    }
+                    Body::Subst(Subst { parse: parse, substs: subst_substs })
+                },
+                Err(err) => Body::Error(err),
+            }),
+            Some(&Code::Expr) => Some(match rstcl::parse_expr_at(contents, inner_start(&token)) {
+                Ok((parse, _)) => {
+                    let mut expr_substs = vec![];
+                    for tok in parse.tokens.iter() {
+                        command_substs(tok, &mut expr_substs, options);
+                    }
+                    Body::Expr(Expr { parse: parse, substs: expr_substs })
+                },
+                Err(err) => Body::Error(err),
+            }),
+            _ => None,
+        }
+    } else {
+        None
+    };
+    return Word { token: token, body: body, substs: substs };
+}
+
+// Parses the scripts of all the command substitutions in a token
+fn command_substs<'a>(token: &TclToken<'a>, acc: &mut Vec<Script<'a>>, options: &ScanOptions) {
+    if token.ttype == TokenType::Command {
+        let val = token.val;
+        acc.push(parse_script_at(&val[1..val.len()-1], inner_start(token), options));
+    }
+    for tok in token.tokens.iter() {
+        command_substs(tok, acc, options);
+    }
+}
@@ -0,0 +1,125 @@
+//! Baselines of known findings, so that only new ones are reported. Findings
+//! are identified by a fingerprint of where they are that doesn't depend on
+//! line numbers, so they still match when code around them changes.
+
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use report::Finding;
+
+/// A finding (or several identical findings) recorded in a baseline
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub fingerprint: String,
+    pub rule: String,
+    pub file: String,
+    /// The command containing the finding, with whitespace normalized
+    pub context: String,
+    /// How many findings have this fingerprint
+    pub count: usize,
+}
+
+/// The findings of a run, as written by `--write-baseline`
+///
+/// ```
+/// use tclscan::baseline::Baseline;
+/// use tclscan::report::Finding;
+/// use tclscan::scan_script;
+/// fn findings(file: &str, script: &str) -> Vec<Finding> {
+///     return scan_script(script).iter().map(|result| Finding::new(file, result)).collect();
+/// }
+/// let old = findings("./a.tcl", "eval $x\nif [y] {}\neval $x");
+/// let json = Baseline::new(&old).to_json();
+/// let mut baseline = Baseline::from_json(&json).unwrap();
+/// // Moving code around and reformatting it doesn't matter, new findings do
+/// let new = findings("a.tcl", "puts hello\neval  $x\nif [z] {}\nif [y] {}");
+/// let new: Vec<_> = new.into_iter().filter(|finding| !baseline.take(finding)).collect();
+/// assert!(new.len() == 1 && new[0].context == "if [z] {}\n");
+/// // One of the two `eval $x` has been fixed
+/// let fixed = baseline.remaining();
+/// assert!(fixed.len() == 1 && fixed[0].context == "eval $x" && fixed[0].count == 1);
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Baseline {
+    pub version: u32,
+    pub entries: Vec<Entry>,
+}
+impl Baseline {
+    pub fn new(findings: &[Finding]) -> Baseline {
+        let mut entries: Vec<Entry> = vec![];
+        let mut index: HashMap<String, usize> = HashMap::new();
+        for finding in findings.iter() {
+            let fingerprint = fingerprint(finding);
+            if let Some(&i) = index.get(&fingerprint) {
+                entries[i].count += 1;
+                continue;
+            }
+            index.insert(fingerprint.clone(), entries.len());
+            entries.push(Entry {
+                fingerprint: fingerprint,
+                rule: finding.rule.clone(),
+                file: normalize_path(&finding.file),
+                context: normalize_code(&finding.context),
+                count: 1,
+            });
+        }
+        return Baseline { version: 1, entries: entries };
+    }
+
+    pub fn from_json(json: &str) -> Result<Baseline, String> {
+        let baseline: Baseline = serde_json::from_str(json).map_err(|err| err.to_string())?;
+        if baseline.version != 1 {
+            return Err(format!("unknown baseline version {}", baseline.version));
+        }
+        return Ok(baseline);
+    }
+
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string_pretty(self).expect("baseline couldn't be serialized");
+    }
+
+    /// Whether the finding is in the baseline. Each entry only matches as
+    /// many findings as it was recorded for.
+    pub fn take(&mut self, finding: &Finding) -> bool {
+        let fingerprint = fingerprint(finding);
+        match self.entries.iter_mut().find(|entry| entry.fingerprint == fingerprint && entry.count > 0) {
+            Some(entry) => {
+                entry.count -= 1;
+                return true;
+            },
+            None => return false,
+        }
+    }
+
+    /// The entries which haven't been matched by `take`, i.e. the findings
+    /// which have been fixed since the baseline was written
+    pub fn remaining(self) -> Vec<Entry> {
+        return self.entries.into_iter().filter(|entry| entry.count > 0).collect();
+    }
+}
+
+/// Identifies a finding by its rule, file, command and problem code
+pub fn fingerprint(finding: &Finding) -> String {
+    let token = finding.token.as_ref().map(|token| normalize_code(token)).unwrap_or_default();
+    let parts = [&*finding.rule, &*normalize_path(&finding.file), &*normalize_code(&finding.context), &*token];
+    // FNV-1a, which unlike std's hashers is stable across releases
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts.iter() {
+        for &b in part.as_bytes().iter().chain(&[0]) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    return format!("{:016x}", hash);
+}
+
+fn normalize_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    return path.trim_start_matches("./").to_owned();
+}
+
+// Collapses runs of whitespace (including escaped newlines) so reformatting
+// doesn't change the fingerprint
+fn normalize_code(code: &str) -> String {
+    return code.replace("\\\n", " ").split_whitespace().collect::<Vec<_>>().join(" ");
+}
@@ -0,0 +1,202 @@
+//! Project configuration, read from a `.tclscan.toml` file in the scanned
+//! directory or one of its parents. Command line options override it.
+//!
+//! ```
+//! # use tclscan::config::Config;
+//! # use tclscan::signatures::Signature;
+//! let config = Config::parse(r#"
+//! ## Defaults for --format and --fail-on
+//! format = "json"
+//! fail-on = "danger"
+//!
+//! [rules]
+//! disable = ["unquoted-block"]
+//! ## warn or danger
+//! severity = { non-literal-command = "danger" }
+//!
+//! [files]
+//! ## Searched for in directories, as well as the usual extensions
+//! extensions = ["tk"]
+//! include = ["src/*"]
+//! exclude = ["vendor/*"]
+//!
+//! [commands]
+//! ## Commands whose results are safe to substitute, as the words they start with
+//! safe = ["db::quote", "string length"]
+//! ## How your own commands interpret their arguments (see `signatures`). Names
+//! ## with characters other than letters, digits, `-` and `_` need quotes.
+//! signatures = { with_lock = "literal block", "db::each" = "-fields=normal literal block" }
+//! ## Files of more signatures, relative to this file
+//! signature-files = ["tools/signatures.toml"]
+//! "#).unwrap();
+//! assert!(config.commands.signatures.values().all(|signature| Signature::parse(signature).is_ok()));
+//! assert!(config.commands.signature_files == vec!["tools/signatures.toml"]);
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use toml;
+use rules;
+use signatures::Signatures;
+use {ScanOptions, Severity};
+
+pub const FILE_NAME: &'static str = ".tclscan.toml";
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub format: Option<String>,
+    pub fail_on: Option<String>,
+    pub rules: RulesConfig,
+    pub files: FilesConfig,
+    pub commands: CommandsConfig,
+    /// The directory the config was loaded from (canonicalised), which paths
+    /// and globs in it are relative to
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesConfig {
+    pub disable: Vec<String>,
+    pub severity: BTreeMap<String, Severity>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilesConfig {
+    pub extensions: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CommandsConfig {
+    pub safe: Vec<String>,
+    pub signatures: BTreeMap<String, String>,
+    pub signature_files: Vec<String>,
+}
+
+impl Config {
+    /// ```
+    /// use tclscan::config::Config;
+    /// use tclscan::{scan_script_with, Severity};
+    /// let config = Config::parse("
+    ///     [rules]
+    ///     disable = ['unquoted-expr']
+    ///     severity = { non-literal-command = 'danger' }
+    ///     [commands]
+    ///     safe = ['db::quote']
+    ///     signatures = { with_lock = 'literal block' }
+    /// ").unwrap();
+    /// assert!(config.commands.signatures["with_lock"] == "literal block");
+    /// let options = config.scan_options().unwrap();
+    /// let results = scan_script_with("eval [db::quote $x]\nif [llength $x] {}\nwith_lock l $y\n$cmd", &options);
+    /// assert!(results.len() == 3);
+    /// // db::quote's result is safe, and with_lock's second argument is a script
+    /// assert!(results[0].rule().id == "unquoted-block");
+    /// assert!(results[1].rule().id == "dangerous-unquoted-block" && results[1].span().start.line == 3);
+    /// assert!(results[2].rule().id == "non-literal-command" && results[2].severity() == Severity::Danger);
+    /// assert!(Config::parse("[rules]\ndisable = ['no-such-rule']").unwrap().scan_options().is_err());
+    /// assert!(Config::parse("[rule]").is_err());
+    /// assert!(Config::parse("[commands]\nsignatures = { x = 'block (' }").unwrap().scan_options().is_err());
+    /// ```
+    pub fn parse(string: &str) -> Result<Config, String> {
+        return toml::from_str(string).map_err(|err| err.to_string());
+    }
+
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let string = fs::read_to_string(path)
+            .map_err(|err| format!("Couldn't read {}: {}", path.display(), err))?;
+        let mut config = Config::parse(&string).map_err(|err| format!("Couldn't load {}: {}", path.display(), err))?;
+        let dir = match path.parent() {
+            Some(dir) if dir.as_os_str().len() > 0 => dir,
+            _ => Path::new("."),
+        };
+        config.dir = dir.canonicalize().unwrap_or(dir.to_owned());
+        return Ok(config);
+    }
+
+    /// `path` relative to the directory the config was loaded from, which
+    /// the `[files]` globs are matched against, or None if it's outside it
+    ///
+    /// ```
+    /// use std::{env, fs};
+    /// use std::path::Path;
+    /// use tclscan::config::Config;
+    /// let proj = env::temp_dir().join(format!("tclscan-relative-path-{}", std::process::id()));
+    /// fs::create_dir_all(proj.join("src")).unwrap();
+    /// fs::create_dir_all(proj.join("vendor")).unwrap();
+    /// fs::write(proj.join(".tclscan.toml"), "[files]\nexclude = ['vendor/*']\n").unwrap();
+    /// fs::write(proj.join("vendor/a.tcl"), "").unwrap();
+    /// // Wherever it's run from and however the path is given
+    /// env::set_current_dir(proj.join("src")).unwrap();
+    /// let config = Config::load(&Config::discover(Path::new("..")).unwrap()).unwrap();
+    /// assert!(config.relative_path(Path::new("../vendor/a.tcl")) == Some(Path::new("vendor/a.tcl").to_owned()));
+    /// assert!(config.relative_path(&proj.join("vendor/a.tcl")) == Some(Path::new("vendor/a.tcl").to_owned()));
+    /// assert!(config.relative_path(&env::temp_dir()).is_none());
+    /// fs::remove_dir_all(&proj).unwrap();
+    /// ```
+    pub fn relative_path(&self, path: &Path) -> Option<PathBuf> {
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(_) => return None,
+        };
+        return path.strip_prefix(&self.dir).ok().map(|path| path.to_owned());
+    }
+
+    /// The config file which applies to `path`: the first found in the
+    /// directory (or directory of the file) or its parents
+    pub fn discover(path: &Path) -> Option<PathBuf> {
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(_) => return None,
+        };
+        let mut dir = if path.is_dir() { Some(&*path) } else { path.parent() };
+        while let Some(d) = dir {
+            let candidate = d.join(FILE_NAME);
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+            dir = d.parent();
+        }
+        return None;
+    }
+
+    /// The options to scan with, checking the rules named exist and loading
+    /// signature files
+    pub fn scan_options(&self) -> Result<ScanOptions, String> {
+        let find = |id: &str| rules::find(id).ok_or(format!("unknown rule {}", id));
+        let mut options = ScanOptions::default();
+        for id in self.rules.disable.iter() {
+            options.disabled_rules.push(find(id)?);
+        }
+        for (id, &severity) in self.rules.severity.iter() {
+            let rule = find(id)?;
+            let swappable = |severity| severity == Severity::Warn || severity == Severity::Danger;
+            if !swappable(rule.severity) || !swappable(severity) {
+                return Err(format!("the severity of {} can't be changed to {}", id, severity));
+            }
+            options.severities.push((rule, severity));
+        }
+        options.safe_commands = self.commands.safe.iter()
+            .map(|cmd| cmd.split_whitespace().map(|word| word.to_owned()).collect())
+            .collect();
+        for file in self.commands.signature_files.iter() {
+            let path = self.dir.join(file);
+            let string = fs::read_to_string(&path)
+                .map_err(|err| format!("Couldn't read {}: {}", path.display(), err))?;
+            let signatures = Signatures::parse_toml(&string)
+                .map_err(|err| format!("Couldn't load {}: {}", path.display(), err))?;
+            options.signatures.extend(signatures);
+        }
+        for (cmd, signature) in self.commands.signatures.iter() {
+            options.signatures.insert(cmd, signature)?;
+        }
+        return Ok(options);
+    }
+}
@@ -1,118 +1,222 @@
-#![feature(core)]
-//#![feature(collections)]
-#![feature(libc)]
-#![feature(slice_patterns)]
-//#![feature(str_char)]
-#![feature(rustc_private)]
-
-extern crate libc;
-// https://github.com/rust-lang/rust/issues/16920
-#[macro_use] extern crate enum_primitive;
-extern crate num;
+// The code base predates clippy and keeps its own idioms (explicit returns,
+// explicit lifetimes, `.into_iter()` when extending etc)
+#![allow(clippy::needless_return, clippy::redundant_field_names, clippy::len_zero)]
+#![allow(clippy::useless_conversion, clippy::needless_lifetimes, clippy::redundant_static_lifetimes)]
+#![allow(clippy::match_like_matches_macro, clippy::match_ref_pats)]
 
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+extern crate toml;
+
+use std::borrow::Cow;
 use std::iter;
 use std::fmt;
 use self::CheckResult::*; // TODO: why does swapping this line with one below break?
-use rstcl::TokenType;
+use serde::{Deserialize, Serialize};
+use rstcl::{ParseError, Position, Span, TokenType};
+use rules::Rule;
 
 pub mod rstcl;
-#[allow(dead_code, non_upper_case_globals, non_camel_case_types, non_snake_case, raw_pointer_derive)]
-mod tcl;
-
-// http://www.tcl.tk/doc/howto/stubs.html
-// Ideally would use stubs but they seem to not work
+pub mod ast;
+pub mod visit;
+pub mod report;
+pub mod rules;
+pub mod baseline;
+pub mod config;
+pub mod signatures;
+mod parser;
+mod suppress;
+mod taint;
 
-// When https://github.com/crabtw/rust-bindgen/issues/89 is fixed
-//#![feature(phase)]
-//#[phase(plugin)] extern crate bindgen;
-//
-//#[allow(dead_code, uppercase_variables, non_camel_case_types)]
-//mod tcl_bindings {
-//    bindgen!("./mytcl.h", match="tcl.h", link="tclstub")
-//}
+/// How bad a `CheckResult` is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warn,
+    Danger,
+    /// The code couldn't be parsed, so wasn't scanned
+    Error,
+}
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.write_str(match *self {
+            Severity::Warn => "warn",
+            Severity::Danger => "danger",
+            Severity::Error => "error",
+        });
+    }
+}
 
 #[derive(PartialEq)]
 pub enum CheckResult<'a> {
-    // context, message, problem code
-    Warn(&'a str, &'static str, &'a str),
-    Danger(&'a str, &'static str, &'a str),
+    // context, rule broken, problem code, location of problem code
+    Warn(&'a str, &'static Rule, &'a str, Span),
+    Danger(&'a str, &'static Rule, &'a str, Span),
+    // code which couldn't be parsed (and so wasn't scanned), why
+    ParseFailure(&'a str, ParseError),
+}
+impl<'a> CheckResult<'a> {
+    /// Where the problem code is in the scanned file
+    pub fn span(&self) -> Span {
+        return match *self {
+            Warn(_, _, _, span) | Danger(_, _, _, span) => span,
+            ParseFailure(_, ref err) => Span { start: err.position, end: err.position },
+        };
+    }
+    pub fn rule(&self) -> &'static Rule {
+        return match *self {
+            Warn(_, rule, _, _) | Danger(_, rule, _, _) => rule,
+            ParseFailure(..) => &rules::PARSE_FAILURE,
+        };
+    }
+    pub fn severity(&self) -> Severity {
+        return match *self {
+            Warn(..) => Severity::Warn,
+            Danger(..) => Severity::Danger,
+            ParseFailure(..) => Severity::Error,
+        };
+    }
 }
 impl<'b> fmt::Display for CheckResult<'b> {
     fn fmt<'a>(&'a self, f: &mut fmt::Formatter) -> fmt::Result {
         return match self {
-            &Warn(ctx, msg, line) => write!(f, "WARN: {} at `{}` in `{}`", msg, line, ctx),
-            &Danger(ctx, msg, line) => write!(f, "DANGER: {} at `{}` in `{}`", msg, line, ctx),
+            &Warn(ctx, rule, line, _) => write!(f, "WARN ({}): {} at `{}` in `{}`", rule.id, rule.message, line, ctx),
+            &Danger(ctx, rule, line, _) => write!(f, "DANGER ({}): {} at `{}` in `{}`", rule.id, rule.message, line, ctx),
+            &ParseFailure(ctx, ref err) => write!(f, "ERROR ({}): {} ({}) in `{}`", rules::PARSE_FAILURE.id, err.message, err.result, ctx),
         };
     }
 }
 
-#[derive(Clone)]
-enum Code {
+/// How a command interprets one of its arguments
+#[derive(Clone, Debug, PartialEq)]
+pub enum Code {
+    /// A script, which should be braced
     Block,
+    /// An expression, which should be braced
     Expr,
+    /// A name (e.g. of a variable to set), which shouldn't be substituted
     Literal,
+    /// Any value
     Normal,
+    /// A list of alternating patterns and scripts, like the last argument of
+    /// `switch`, which should be braced
+    PatternBodies,
+    /// A string which is substituted, like the argument of `subst`, which
+    /// should be braced
+    Subst,
+    /// A script run later by the event loop, like the script of `after`,
+    /// which should be braced or built with `list`
+    Callback,
+    /// A list of a command and its first arguments, which more arguments are
+    /// added to before it's run, like the value of `lsort -command`
+    CommandPrefix,
 }
 
 fn check_literal<'a, 'b>(ctx: &'a str, token: &'b rstcl::TclToken<'a>) -> Vec<CheckResult<'a>> {
     let token_str = token.val;
     assert!(token_str.len() > 0);
-    return if token_str.chars().nth(0) == Some('{') {
+    let contains = |ttype| token.iter().any(|tok| tok.ttype == ttype);
+    return if token.literal().is_some() {
         vec![]
-    } else if token_str.contains('$') {
-        vec![Danger(ctx, "Expected literal, found $", token_str)]
-    } else if token_str.contains('[') {
-        vec![Danger(ctx, "Expected literal, found [", token_str)]
+    } else if contains(TokenType::Variable) {
+        vec![Danger(ctx, &rules::VARIABLE_IN_LITERAL, token_str, token.span)]
+    } else if contains(TokenType::Command) {
+        vec![Danger(ctx, &rules::COMMAND_IN_LITERAL, token_str, token.span)]
     } else {
         vec![]
     }
 }
 
 // Does this variable only contain safe characters?
-// Only used by is_safe_val
-fn is_safe_var(token: &rstcl::TclToken) -> bool {
+// Only used by val_safety
+fn var_safety(token: &rstcl::TclToken, vars: &taint::Vars) -> Safety {
     assert!(token.ttype == TokenType::Variable);
-    return false
+    return vars.safety(token);
 }
 
 // Does the return value of this function only contain safe characters?
-// Only used by is_safe_val.
-fn is_safe_cmd(token: &rstcl::TclToken) -> bool {
+// Only used by val_safety.
+fn is_safe_cmd(token: &rstcl::TclToken, options: &ScanOptions) -> bool {
     let string = token.val;
     assert!(string.starts_with("[") && string.ends_with("]"));
     let script = &string[1..string.len()-1];
-    let parses = rstcl::parse_script(script);
-    // Empty script
-    if parses.len() == 0 {
-        return true;
-    }
-    let token_strs: Vec<&str> = parses[0].tokens.iter().map(|e| e.val).collect();
-    return match &token_strs[..] {
+    // Only the first command matters
+    let parse = match rstcl::iter_script(script).next() {
+        Some(Ok(parse)) => parse,
+        // Can't tell what it does, so can't vouch for it
+        Some(Err(_)) => return false,
+        // Empty script
+        None => return true,
+    };
+    let token_strs: Vec<Cow<str>> = parse.tokens.iter()
+        .map(|e| e.literal().unwrap_or(Cow::Borrowed(e.val))).collect();
+    let token_strs: Vec<&str> = token_strs.iter().map(|e| &**e).collect();
+    let is_safe = match &token_strs[..] {
         ["llength", _] |
+        ["incr", ..] |
+        ["string", "length", _] |
+        ["string", "is", ..] |
         ["clock", "seconds"] |
+        ["clock", "clicks", ..] |
+        ["clock", "milliseconds"] |
+        ["clock", "microseconds"] |
         ["info", "exists", ..] |
         ["catch", ..] => true,
         _ => false,
     };
+    return is_safe || options.safe_commands.iter().any(|words| {
+        words.len() <= token_strs.len() && words.iter().zip(token_strs.iter()).all(|(word, token_str)| word == token_str)
+    });
+}
+
+/// Where a value can be substituted without changing what code runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Safety {
+    /// Nowhere, as it may hold `[`, `$` or `\`
+    Unsafe,
+    /// In an expression, but it may be several words or commands in a script
+    Expr,
+    /// Anywhere, e.g. a number
+    Script,
+}
+
+// Where a decoded string can be substituted
+fn literal_safety(value: &str) -> Safety {
+    return if value.contains(['[', '$', '\\']) {
+        Safety::Unsafe
+    } else if value.contains(|c: char| c.is_whitespace() || c == ';') {
+        Safety::Expr
+    } else {
+        Safety::Script
+    };
 }
 
 // Check whether a value can ever cause or assist in any security flaw i.e.
 // whether it may contain special characters.
 // We do *not* concern ourselves with vulnerabilities in sub-commands. That
 // should happen elsewhere.
-fn is_safe_val(token: &rstcl::TclToken) -> bool {
+fn val_safety(token: &rstcl::TclToken, options: &ScanOptions, vars: &taint::Vars) -> Safety {
     assert!(token.val.len() > 0);
+    let mut safety = Safety::Script;
     for tok in token.iter() {
-        let is_safe = match tok.ttype {
-            TokenType::Variable => is_safe_var(tok),
-            TokenType::Command => is_safe_cmd(tok),
-            _ => true,
+        let tok_safety = match tok.ttype {
+            TokenType::Variable => var_safety(tok, vars),
+            TokenType::Command if is_safe_cmd(tok, options) => Safety::Script,
+            TokenType::Command => Safety::Unsafe,
+            // Text is taken as Tcl sees it, e.g. `\[` is a bracket
+            TokenType::Text | TokenType::Bs => tok.literal().map_or(Safety::Unsafe, |value| literal_safety(&value)),
+            _ => Safety::Script,
         };
-        if !is_safe {
-            return false;
-        }
+        safety = safety.min(tok_safety);
     }
-    return true;
+    return safety;
+}
+
+// Is a value safe to use as an argument which should have been braced? One
+// which is entirely literal is what its author wrote, so only unbraced.
+fn is_safe_val(token: &rstcl::TclToken, needed: Safety, options: &ScanOptions, vars: &taint::Vars) -> bool {
+    return token.literal().is_some() || val_safety(token, options, vars) >= needed;
 }
 
 /// Checks if a parsed command is insecure
@@ -121,32 +225,105 @@ fn is_safe_val(token: &rstcl::TclToken) -> bool {
 /// use tclscan::rstcl::parse_command;
 /// use tclscan::check_command;
 /// use tclscan::CheckResult;
-/// use tclscan::CheckResult::{Danger,Warn};
+/// use tclscan::CheckResult::{Danger,Warn,ParseFailure};
+/// use tclscan::rstcl::{Position,Span,ParseError};
+/// use tclscan::rules::*;
+/// fn p(offset: usize, line: usize, col: usize) -> Position {
+///     return Position { offset: offset, line: line, col: col };
+/// }
+/// // A span on the first line
+/// fn s(start: usize, end: usize) -> Span {
+///     return Span { start: p(start, 1, start + 1), end: p(end, 1, end + 1) };
+/// }
 /// fn c<'a>(string: &'a str) -> Vec<CheckResult<'a>> {
-///     return check_command(string, &parse_command(string).0.tokens);
+///     return check_command(string, &parse_command(string).unwrap().0.tokens);
 /// }
 /// assert!(c(("puts x")) == vec![]);
 /// assert!(c(("puts [x]")) == vec![]);
 /// assert!(c(("puts [x\n ]")) == vec![]);
 /// assert!(c(("puts [x;y]")) == vec![]);
-/// assert!(c(("puts [x;eval $y]")) == vec![Danger("eval $y", "Dangerous unquoted block", "$y")]);
-/// assert!(c(("puts [;;eval $y]")) == vec![Danger("eval $y", "Dangerous unquoted block", "$y")]);
-/// assert!(c(("puts [eval $x]")) == vec![Danger("eval $x", "Dangerous unquoted block", "$x")]);
+/// assert!(c(("puts [x;eval $y]")) == vec![Danger("eval $y", &DANGEROUS_UNQUOTED_BLOCK, "$y", s(13, 15))]);
+/// assert!(c(("puts [;;eval $y]")) == vec![Danger("eval $y", &DANGEROUS_UNQUOTED_BLOCK, "$y", s(13, 15))]);
+/// assert!(c(("puts [eval $x]")) == vec![Danger("eval $x", &DANGEROUS_UNQUOTED_BLOCK, "$x", s(11, 13))]);
 /// assert!(c(("expr {[blah]}")) == vec![]);
-/// assert!(c(("expr \"[blah]\"")) == vec![Danger("expr \"[blah]\"", "Dangerous unquoted expr", "\"[blah]\"")]);
+/// assert!(c(("expr \"[blah]\"")) == vec![Danger("expr \"[blah]\"", &DANGEROUS_UNQUOTED_EXPR, "\"[blah]\"", s(5, 13))]);
 /// assert!(c(("expr {\\\n0}")) == vec![]);
-/// assert!(c(("expr {[expr \"[blah]\"]}")) == vec![Danger("expr \"[blah]\"", "Dangerous unquoted expr", "\"[blah]\"")]);
-/// assert!(c(("if [info exists abc] {}")) == vec![Warn("if [info exists abc] {}", "Unquoted expr", "[info exists abc]")]);
-/// assert!(c(("if [abc] {}")) == vec![Danger("if [abc] {}", "Dangerous unquoted expr", "[abc]")]);
-/// assert!(c(("a${x} blah")) == vec![Warn("a${x} blah", "Non-literal command, cannot scan", "a${x}")]);
+/// assert!(c(("expr {[expr \"[blah]\"]}")) == vec![Danger("expr \"[blah]\"", &DANGEROUS_UNQUOTED_EXPR, "\"[blah]\"", s(12, 20))]);
+/// assert!(c(("if [info exists abc] {}")) == vec![Warn("if [info exists abc] {}", &UNQUOTED_EXPR, "[info exists abc]", s(3, 20))]);
+/// assert!(c(("if [abc] {}")) == vec![Danger("if [abc] {}", &DANGEROUS_UNQUOTED_EXPR, "[abc]", s(3, 8))]);
+/// assert!(c(("a${x} blah")) == vec![Warn("a${x} blah", &NON_LITERAL_COMMAND, "a${x}", s(0, 5))]);
 /// assert!(c(("set a []")) == vec![]);
+/// assert!(c(("\\x65val $x")) == vec![Danger("\\x65val $x", &DANGEROUS_UNQUOTED_BLOCK, "$x", s(8, 10))]);
+/// assert!(c(("if {$x} {} \"else\" $y")) == vec![Danger("if {$x} {} \"else\" $y", &DANGEROUS_UNQUOTED_BLOCK, "$y", s(18, 20))]);
+/// assert!(c(("proc \\$x {} {}")) == vec![]);
+/// assert!(c(("proc $x {} {}")) == vec![Danger("proc $x {} {}", &VARIABLE_IN_LITERAL, "$x", s(5, 7))]);
+/// assert!(c(("switch -glob -- $x {a - b {eval $y}}")) == vec![Danger("eval $y", &DANGEROUS_UNQUOTED_BLOCK, "$y", s(32, 34))]);
+/// assert!(c(("switch $x a {eval $y} default $z")) == vec![
+///     Danger("eval $y", &DANGEROUS_UNQUOTED_BLOCK, "$y", s(18, 20)),
+///     Danger("switch $x a {eval $y} default $z", &DANGEROUS_UNQUOTED_BLOCK, "$z", s(30, 32)),
+/// ]);
+/// assert!(c(("switch {*}$opts $x {a {eval $y}}")) == vec![Danger("eval $y", &DANGEROUS_UNQUOTED_BLOCK, "$y", s(28, 30))]);
+/// assert!(c(("switch -- {a b}")) == vec![Warn("switch -- {a b}", &BADLY_FORMED_COMMAND, "switch", s(0, 6))]);
+/// assert!(c(("if {1} {} else")) == vec![Warn("if {1} {} else", &BADLY_FORMED_COMMAND, "if", s(0, 2))]);
+/// assert!(c(("switch $x {# a\n a {}}")) == vec![Warn("switch $x {# a\n a {}}", &COMMENT_IN_SWITCH, "#", s(11, 12))]);
+/// assert!(c(("switch $x \"a {$y}\"")) == vec![Danger("switch $x \"a {$y}\"", &DANGEROUS_UNQUOTED_BLOCK, "\"a {$y}\"", s(10, 18))]);
+/// assert!(c(("uplevel 1 $x")) == vec![Danger("uplevel 1 $x", &DANGEROUS_UNQUOTED_BLOCK, "$x", s(10, 12))]);
+/// assert!(c(("namespace eval ns {eval $x}")) == vec![Danger("eval $x", &DANGEROUS_UNQUOTED_BLOCK, "$x", s(24, 26))]);
+/// assert!(c(("try {} on error {m o} {eval $x} finally {}")) == vec![Danger("eval $x", &DANGEROUS_UNQUOTED_BLOCK, "$x", s(28, 30))]);
+/// assert!(c(("subst {a [eval $x]}")) == vec![Danger("eval $x", &DANGEROUS_UNQUOTED_BLOCK, "$x", s(15, 17))]);
+/// assert!(c(("subst -nocommands {[eval $x]}")) == vec![]);
+/// assert!(c(("subst -nocommands {$a([eval $x])}")) == vec![Danger("eval $x", &DANGEROUS_UNQUOTED_BLOCK, "$x", s(28, 30))]);
+/// assert!(c(("subst -novariables {$a([eval $x])}")) == vec![Danger("eval $x", &DANGEROUS_UNQUOTED_BLOCK, "$x", s(29, 31))]);
+/// assert!(c(("subst -nocommands -novariables $x")) == vec![]);
+/// assert!(c(("subst -nobackslashes $x")) == vec![Danger("subst -nobackslashes $x", &DANGEROUS_UNQUOTED_BLOCK, "$x", s(21, 23))]);
+/// assert!(c(("after 100 [list handle $x]")) == vec![]);
+/// assert!(c(("after idle {eval $x}")) == vec![Danger("eval $x", &DANGEROUS_UNQUOTED_BLOCK, "$x", s(17, 19))]);
+/// assert!(c(("fileevent $s readable \"handle $s\"")) == vec![Danger("fileevent $s readable \"handle $s\"", &DANGEROUS_CALLBACK, "\"handle $s\"", s(22, 33))]);
+/// assert!(c(("after cancel $id")) == vec![]);
+/// assert!(c(("after 100 $callback")) == vec![]);
+/// assert!(c(("after 100 \"$callback $x\"")) == vec![Danger("after 100 \"$callback $x\"", &DANGEROUS_CALLBACK, "\"$callback $x\"", s(10, 24))]);
+/// assert!(c(("after idle $a $b")) == vec![
+///     Danger("after idle $a $b", &DANGEROUS_CALLBACK, "$a", s(11, 13)),
+///     Danger("after idle $a $b", &DANGEROUS_CALLBACK, "$b", s(14, 16)),
+/// ]);
+/// assert!(c(("after 100 [list handle] $data")) == vec![
+///     Danger("after 100 [list handle] $data", &DANGEROUS_CALLBACK, "[list handle]", s(10, 23)),
+///     Danger("after 100 [list handle] $data", &DANGEROUS_CALLBACK, "$data", s(24, 29)),
+/// ]);
+/// assert!(c(("after 100 handle $data")) == vec![
+///     Warn("after 100 handle $data", &UNQUOTED_BLOCK, "handle", s(10, 16)),
+///     Danger("after 100 handle $data", &DANGEROUS_CALLBACK, "$data", s(17, 22)),
+/// ]);
+/// assert!(c(("eval [list a $b] [lrange $c 1 end]")) == vec![]);
+/// assert!(c(("eval [concat a $b]")) == vec![Danger("eval [concat a $b]", &DANGEROUS_UNQUOTED_BLOCK, "[concat a $b]", s(5, 18))]);
+/// assert!(c(("eval \"[list a] $b\"")) == vec![Danger("eval \"[list a] $b\"", &DANGEROUS_UNQUOTED_BLOCK, "\"[list a] $b\"", s(5, 18))]);
+/// assert!(c(("eval {*}$args")) == vec![Danger("eval {*}$args", &DANGEROUS_UNQUOTED_BLOCK, "{*}$args", s(5, 13))]);
+/// assert!(c(("eval {*}${x}")) == vec![Danger("eval {*}${x}", &DANGEROUS_UNQUOTED_BLOCK, "{*}${x}", s(5, 12))]);
+/// assert!(c(("expr {*}${x}")) == vec![Danger("expr {*}${x}", &DANGEROUS_UNQUOTED_EXPR, "{*}${x}", s(5, 12))]);
+/// assert!(c(("uplevel 1 {*}${x}")) == vec![Danger("uplevel 1 {*}${x}", &DANGEROUS_UNQUOTED_BLOCK, "{*}${x}", s(10, 17))]);
+/// assert!(c(("if {*}${x} {}")) == vec![Danger("if {*}${x} {}", &DANGEROUS_UNQUOTED_EXPR, "{*}${x}", s(3, 10))]);
+/// assert!(c(("{*}$cmd $x")) == vec![Warn("{*}$cmd $x", &NON_LITERAL_COMMAND, "{*}$cmd", s(0, 7))]);
+/// assert!(c(("{*}{puts -nonewline} {*}$args")) == vec![]);
+/// assert!(c(("lsort -command [list cmp $x] $l")) == vec![]);
+/// assert!(c(("lsort -command $cmp -integer $l")) == vec![]);
+/// assert!(c(("lsort -command [concat cmp $x] $l")) == vec![Danger("lsort -command [concat cmp $x] $l", &DANGEROUS_COMMAND_PREFIX, "[concat cmp $x]", s(15, 30))]);
+/// assert!(c(("trace add variable v write \"log $x\"")) == vec![Danger("trace add variable v write \"log $x\"", &DANGEROUS_COMMAND_PREFIX, "\"log $x\"", s(27, 35))]);
+/// assert!(c(("eval {x; puts \"a}")) == vec![ParseFailure("puts \"a", ParseError {
+///     message: "couldn't parse command", position: p(14, 1, 15), result: "missing \"".to_owned(),
+/// })]);
+/// assert!(c(("if {1} {\n  eval $y\n}")) == vec![Danger("eval $y\n", &DANGEROUS_UNQUOTED_BLOCK, "$y",
+///     Span { start: p(16, 2, 8), end: p(18, 2, 10) })]);
 /// ```
 pub fn check_command<'a, 'b>(ctx: &'a str, tokens: &'b Vec<rstcl::TclToken<'a>>) -> Vec<CheckResult<'a>> {
+    return check_command_with(ctx, tokens, &ScanOptions::default(), &mut taint::Vars::default());
+}
+
+fn check_command_with<'a, 'b>(ctx: &'a str, tokens: &'b Vec<rstcl::TclToken<'a>>, options: &ScanOptions, vars: &mut taint::Vars) -> Vec<CheckResult<'a>> {
     let mut results = vec![];
     // First check all subcommands which will be substituted
     for tok in tokens.iter() {
         for subtok in tok.iter().filter(|tok| tok.ttype == TokenType::Command) {
-            results.extend(scan_command(subtok.val).into_iter());
+            results.extend(scan_command_with(subtok, options, vars).into_iter());
         }
     }
     // The empty command (caused by e.g. `[]`, `;;`, last parse in a script)
@@ -155,114 +332,502 @@ pub fn check_command<'a, 'b>(ctx: &'a str, tokens: &'b Vec<rstcl::TclToken<'a>>)
     }
     // Now check if the command name itself isn't a literal
     if check_literal(ctx, &tokens[0]).into_iter().len() > 0 {
-        results.push(Warn(ctx, "Non-literal command, cannot scan", tokens[0].val));
+        vars.clear();
+        results.push(Warn(ctx, &rules::NON_LITERAL_COMMAND, tokens[0].val, tokens[0].span));
         return results;
     }
     // Now check the command-specific interpretation of arguments etc
-    let param_types = match tokens[0].val {
-        // eval script
-        "eval" => iter::repeat(Code::Block).take(tokens.len()-1).collect(),
-        // catch script [result]? [options]?
-        "catch" => {
-            let mut param_types = vec![Code::Block];
-            if tokens.len() == 3 || tokens.len() == 4 {
-                let new_params: Vec<Code> = iter::repeat(Code::Literal).take(tokens.len()-2).collect();
-                param_types.extend_from_slice(&new_params);
-            }
-            param_types
-        }
-        // expr [arg]+
-        "expr" => tokens[1..].iter().map(|_| Code::Expr).collect(),
-        // proc name args body
-        "proc" => vec![Code::Literal, Code::Literal, Code::Block],
-        // for init cond iter body
-        "for" => vec![Code::Block, Code::Expr, Code::Block, Code::Block],
-        // foreach [varname list]+ body
-        "foreach" => vec![Code::Literal, Code::Normal, Code::Block],
-        // while cond body
-        "while" => vec![Code::Expr, Code::Block],
-        // if cond body [elseif cond body]* [else body]?
-        "if" => {
-            let mut param_types = vec![Code::Expr, Code::Block];
-            let mut i = 3;
-            while i < tokens.len() {
-                param_types.extend_from_slice(&match tokens[i].val {
-                    "elseif" => vec![Code::Literal, Code::Expr, Code::Block],
-                    "else" => vec![Code::Literal, Code::Block],
-                    _ => { break; },
-                });
-                i = param_types.len() + 1;
-            }
-            param_types
+    let param_types = match param_types(tokens, options) {
+        Some(param_types) => param_types,
+        None => {
+            vars.update(tokens, options);
+            results.push(Warn(ctx, &rules::BADLY_FORMED_COMMAND, tokens[0].val, tokens[0].span));
+            return results;
         },
-        _ => iter::repeat(Code::Normal).take(tokens.len()-1).collect(),
     };
-    if param_types.len() != tokens.len() - 1 {
-        results.push(Warn(ctx, "badly formed command", tokens[0].val));
-        return results;
+    let name = tokens[0].literal().unwrap_or_default();
+    let new_scope = taint::is_new_scope(tokens);
+    let sets_vars_first = taint::sets_vars_first(tokens);
+    let params: Vec<(&Code, &rstcl::TclToken<'a>)> = param_types.iter().zip(tokens[1..].iter()).collect();
+    let bodies: Vec<usize> = match new_scope {
+        true => vec![],
+        false => (0..params.len()).filter(|&i| taint::is_body(params[i].0)).collect(),
+    };
+    // Checking a number in the condition of an if makes it safe in the body,
+    // or after it if the check is negated and the body leaves
+    let (guards, negated) = match name.as_ref() {
+        "if" => taint::guards(params[0].1),
+        _ => (vec![], false),
+    };
+    let then_body = params.iter().position(|&(code, _)| *code == Code::Block);
+    // What's safe while the arguments are evaluated: the loop variables of
+    // foreach are set first, the first script of for is run once, and then
+    // any of the others may be run any number of times, so only what they
+    // all keep safe stays safe
+    let mut entry = vars.clone();
+    if sets_vars_first {
+        entry.update(tokens, options);
     }
-    for (param_type, param) in param_types.iter().zip(tokens[1..].iter()) {
-        let check_results: Vec<CheckResult<'a>> = match *param_type {
-            Code::Block => check_block(ctx, param),
-            Code::Expr => check_expr(ctx, param),
-            Code::Literal => check_literal(ctx, param),
-            Code::Normal => vec![],
+    let init = if name == "for" { bodies.first().copied() } else { None };
+    if let Some(i) = init {
+        results.extend(check_block(ctx, params[i].1, options, &mut entry).into_iter());
+    }
+    // The other bodies of an if only run if the condition is false
+    let (body_results, other_vars) = loop {
+        let mut body_results = vec![];
+        let mut other_vars = vec![];
+        let mut next = entry.clone();
+        for &i in bodies.iter().filter(|&&i| Some(i) != init) {
+            let mut body_vars = entry.clone();
+            if (Some(i) == then_body) != negated {
+                for guard in guards.iter() {
+                    body_vars.insert(guard);
+                }
+            }
+            body_results.push((i, check_param(ctx, params[i].0, params[i].1, options, &mut body_vars)));
+            next.intersect(&body_vars);
+            if Some(i) != then_body {
+                other_vars.push(body_vars);
+            }
+        }
+        if next == entry {
+            break (body_results, other_vars);
+        }
+        entry = next;
+    };
+    // Scripts in several words, e.g. `after 100 cmd $arg`, are concatenated
+    let callbacks: Vec<_> = params.iter().filter(|&&(code, _)| *code == Code::Callback).collect();
+    let is_whole_callback = match callbacks[..] {
+        [&(_, callback)] => callback.ttype != TokenType::ExpandWord,
+        _ => false,
+    };
+    let mut body_results = body_results.into_iter().peekable();
+    for (i, &(param_type, param)) in params.iter().enumerate() {
+        let check_results = if Some(i) == init {
+            continue;
+        } else if body_results.peek().is_some_and(|&(body, _)| body == i) {
+            body_results.next().unwrap().1
+        } else if *param_type == Code::Subst {
+            check_subst(ctx, param, subst_flags(tokens), options, &mut entry)
+        } else if *param_type == Code::Callback {
+            check_callback(ctx, param, is_whole_callback, options, &mut taint::Vars::default())
+        } else if new_scope {
+            check_param(ctx, param_type, param, options, &mut taint::Vars::default())
+        } else {
+            check_param(ctx, param_type, param, options, &mut entry)
         };
         results.extend(check_results.into_iter());
     }
+    // A script which isn't braced could do anything, unless it's only made
+    // of safe values
+    let is_dynamic = bodies.iter().any(|&i| {
+        !is_braced(params[i].1) && !is_safe_val(params[i].1, Safety::Script, options, &entry)
+    });
+    *vars = entry;
+    if is_dynamic {
+        vars.clear();
+    }
+    // Loop variables were set before the scripts ran, and may have been
+    // changed by them since
+    if !sets_vars_first {
+        vars.update(tokens, options);
+    }
+    if negated && then_body.is_some_and(|i| taint::exits(params[i].1)) {
+        for guard in guards.iter() {
+            let safety = other_vars.iter().map(|body_vars| body_vars.name_safety(guard)).min().unwrap_or(Safety::Script);
+            vars.set(guard, safety);
+        }
+    }
     return results;
 }
 
+fn check_param<'a, 'b>(ctx: &'a str, param_type: &Code, param: &'b rstcl::TclToken<'a>, options: &ScanOptions, vars: &mut taint::Vars) -> Vec<CheckResult<'a>> {
+    return match *param_type {
+        Code::Block => check_block(ctx, param, options, vars),
+        Code::Expr => check_expr(ctx, param, options, vars),
+        Code::Literal => check_literal(ctx, param),
+        Code::Normal => vec![],
+        Code::PatternBodies => check_pattern_bodies(ctx, param, options, vars),
+        Code::Subst => check_subst(ctx, param, rstcl::SubstFlags::default(), options, vars),
+        Code::Callback => check_callback(ctx, param, true, options, vars),
+        Code::CommandPrefix => check_command_prefix(ctx, param, options, vars),
+    };
+}
+
+/// How each argument of a (non-empty, literally named) command is
+/// interpreted, or None if the arguments don't fit the command
+fn param_types(tokens: &[rstcl::TclToken], options: &ScanOptions) -> Option<Vec<Code>> {
+    // Commands and keywords are matched on their values, so e.g. `\x65val`
+    // is still eval
+    let name = tokens[0].literal();
+    let name = name.as_deref().unwrap_or("");
+    let find = |name: &str| options.signatures.get(name).or_else(|| signatures::Signatures::builtin().get(name));
+    // Subcommands, e.g. `namespace eval`, can have signatures of their own
+    let subcommand = tokens.get(1).and_then(|token| token.literal());
+    if let Some(signature) = subcommand.and_then(|sub| find(&format!("{} {}", name, sub))) {
+        return signature.arg_codes(&tokens[2..]).map(|codes| {
+            let mut param_types = vec![Code::Literal];
+            param_types.extend(codes.into_iter());
+            param_types
+        });
+    }
+    return match find(name) {
+        Some(signature) => signature.arg_codes(&tokens[1..]),
+        None => Some(iter::repeat_n(Code::Normal, tokens.len()-1).collect()),
+    };
+}
+
 /// Scans a block (i.e. should be quoted) for danger
-fn check_block<'a, 'b>(ctx: &'a str, token: &'b rstcl::TclToken<'a>) -> Vec<CheckResult<'a>> {
+fn check_block<'a, 'b>(ctx: &'a str, token: &'b rstcl::TclToken<'a>, options: &ScanOptions, vars: &mut taint::Vars) -> Vec<CheckResult<'a>> {
     let block_str = token.val;
-    if !(block_str.starts_with("{") && block_str.ends_with("}")) {
-        return vec!(match is_safe_val(token) {
-            true => Warn(ctx, "Unquoted block", block_str),
-            false => Danger(ctx, "Dangerous unquoted block", block_str),
+    if is_list_cmd(token) {
+        return vec![];
+    }
+    if !is_braced(token) {
+        return vec!(match is_safe_val(token, Safety::Script, options, vars) {
+            true => Warn(ctx, &rules::UNQUOTED_BLOCK, block_str, token.span),
+            false => Danger(ctx, &rules::DANGEROUS_UNQUOTED_BLOCK, block_str, token.span),
         });
     }
     // Block isn't inherently dangerous, let's check functions inside the block
     let script_str = &block_str[1..block_str.len()-1];
-    return scan_script(script_str);
+    return scan_script_at(script_str, inner_start(token), options, vars);
+}
+
+/// Scans a callback script (i.e. should be quoted or a list) for danger,
+/// `whole` if it's the whole script rather than one of several words which
+/// are concatenated to make it
+fn check_callback<'a, 'b>(ctx: &'a str, token: &'b rstcl::TclToken<'a>, whole: bool, options: &ScanOptions, vars: &mut taint::Vars) -> Vec<CheckResult<'a>> {
+    let callback_str = token.val;
+    if is_braced(token) {
+        return check_block(ctx, token, options, vars);
+    }
+    // The result of list is a single command, whatever its arguments are,
+    // and a callback which is passed on whole, e.g. `after 100 $callback`, is
+    // the caller's responsibility
+    if whole && (is_list_cmd(token) || is_whole_var(token)) {
+        return vec![];
+    }
+    return vec!(match is_safe_val(token, Safety::Script, options, vars) {
+        true => Warn(ctx, &rules::UNQUOTED_BLOCK, callback_str, token.span),
+        false => Danger(ctx, &rules::DANGEROUS_CALLBACK, callback_str, token.span),
+    });
+}
+
+/// Scans a command prefix (i.e. should be a literal or a list) for danger
+fn check_command_prefix<'a, 'b>(ctx: &'a str, token: &'b rstcl::TclToken<'a>, options: &ScanOptions, vars: &mut taint::Vars) -> Vec<CheckResult<'a>> {
+    // A prefix which is passed on whole, e.g. `-command $callback`, is the
+    // caller's responsibility, and one built by list is a list already
+    if token.literal().is_some() || is_whole_var(token) || is_list_cmd(token) || is_safe_val(token, Safety::Expr, options, vars) {
+        return vec![];
+    }
+    return vec![Danger(ctx, &rules::DANGEROUS_COMMAND_PREFIX, token.val, token.span)];
+}
+
+// Is this word in braces, e.g. `{puts $a}`, so substituted by the command
+// rather than by Tcl? An expanded word like `{*}${x}` isn't. Braces only
+// hold text, split by any backslash-newlines.
+fn is_braced(token: &rstcl::TclToken) -> bool {
+    let val = token.val;
+    return token.ttype != TokenType::ExpandWord && val.starts_with("{") && val.ends_with("}") &&
+        token.tokens.iter().all(|tok| tok.ttype == TokenType::Text || tok.ttype == TokenType::Bs);
+}
+
+// Is this word just the value of a variable, e.g. `$callback`?
+fn is_whole_var(token: &rstcl::TclToken) -> bool {
+    return match token.tokens[..] {
+        [ref var] => var.ttype == TokenType::Variable,
+        _ => false,
+    };
+}
+
+// Is this word just the result of a command which builds a list, e.g.
+// `[list cmd $a]`? When evaluated, it's a single command whose words are the
+// elements of the list.
+fn is_list_cmd(token: &rstcl::TclToken) -> bool {
+    let subst = match token.tokens[..] {
+        [ref subst] if subst.ttype == TokenType::Command => subst,
+        _ => return false,
+    };
+    let script = &subst.val[1..subst.val.len()-1];
+    let mut commands = rstcl::iter_script(script).filter(|parse| match *parse {
+        Ok(ref parse) => parse.tokens.len() > 0,
+        Err(_) => true,
+    });
+    let parse = match (commands.next(), commands.next()) {
+        (Some(Ok(parse)), None) => parse,
+        _ => return false,
+    };
+    return match parse.tokens[0].literal().as_deref() {
+        Some("list") | Some("lrange") | Some("linsert") | Some("lappend") => true,
+        _ => false,
+    };
+}
+
+/// Scans a list of patterns and bodies (i.e. should be quoted) for danger
+fn check_pattern_bodies<'a, 'b>(ctx: &'a str, token: &'b rstcl::TclToken<'a>, options: &ScanOptions, vars: &mut taint::Vars) -> Vec<CheckResult<'a>> {
+    let list_str = token.val;
+    if !is_braced(token) {
+        return check_block(ctx, token, options, vars);
+    }
+    let elements = match rstcl::parse_list_at(&list_str[1..list_str.len()-1], inner_start(token)) {
+        Ok(elements) => elements,
+        Err(err) => return vec![ParseFailure(ctx, err)],
+    };
+    if elements.len() % 2 != 0 {
+        return vec![Warn(ctx, &rules::BADLY_FORMED_COMMAND, list_str, token.span)];
+    }
+    let mut results = vec![];
+    // Only one of the bodies runs
+    let before = vars.clone();
+    for pair in elements.chunks(2) {
+        let (pattern, body) = (&pair[0], &pair[1]);
+        if pattern.value.starts_with("#") {
+            results.push(Warn(ctx, &rules::COMMENT_IN_SWITCH, pattern.val, pattern.span));
+        }
+        // `-` means use the body of the next pattern
+        if body.value != "-" {
+            let mut body_vars = before.clone();
+            results.extend(scan_script_at(body.val, body.span.start, options, &mut body_vars).into_iter());
+            vars.intersect(&body_vars);
+        }
+    }
+    return results;
+}
+
+/// Scans a string to be substituted (i.e. should be quoted) for danger, when
+/// only the substitutions in `flags` are done
+fn check_subst<'a, 'b>(ctx: &'a str, token: &'b rstcl::TclToken<'a>, flags: rstcl::SubstFlags, options: &ScanOptions, vars: &mut taint::Vars) -> Vec<CheckResult<'a>> {
+    let subst_str = token.val;
+    // Without command or variable substitution (whose array element names
+    // can hold commands), no command can run
+    if !flags.commands && !flags.variables {
+        return vec![];
+    }
+    if !is_braced(token) {
+        return check_block(ctx, token, options, vars);
+    }
+    let parse = match rstcl::parse_subst_flags_at(&subst_str[1..subst_str.len()-1], inner_start(token), flags) {
+        Ok((parse, _)) => parse,
+        Err(err) => return vec![ParseFailure(ctx, err)],
+    };
+    let mut results = vec![];
+    for tok in parse.tokens.iter() {
+        for subtok in tok.iter().filter(|tok| tok.ttype == TokenType::Command) {
+            results.extend(scan_command_with(subtok, options, vars).into_iter());
+        }
+    }
+    return results;
+}
+
+/// The substitutions a command taking a `Code::Subst` argument, like
+/// `subst`, does, going by the options before the argument
+fn subst_flags(tokens: &[rstcl::TclToken]) -> rstcl::SubstFlags {
+    let mut flags = rstcl::SubstFlags::default();
+    for token in tokens.iter().skip(1).take(tokens.len().saturating_sub(2)) {
+        match token.literal().as_deref() {
+            Some("-nobackslashes") => flags.backslashes = false,
+            Some("-nocommands") => flags.commands = false,
+            Some("-novariables") => flags.variables = false,
+            _ => (),
+        }
+    }
+    return flags;
 }
 
 /// Scans an expr (i.e. should be quoted) for danger
-fn check_expr<'a, 'b>(ctx: &'a str, token: &'b rstcl::TclToken<'a>) -> Vec<CheckResult<'a>> {
+fn check_expr<'a, 'b>(ctx: &'a str, token: &'b rstcl::TclToken<'a>, options: &ScanOptions, vars: &mut taint::Vars) -> Vec<CheckResult<'a>> {
     let mut results = vec![];
     let expr_str = token.val;
-    if !(expr_str.starts_with("{") && expr_str.ends_with("}")) {
-        results.push(match is_safe_val(token) {
-            true => Warn(ctx, "Unquoted expr", expr_str),
-            false => Danger(ctx, "Dangerous unquoted expr", expr_str),
+    if !is_braced(token) {
+        results.push(match is_safe_val(token, Safety::Expr, options, vars) {
+            true => Warn(ctx, &rules::UNQUOTED_EXPR, expr_str, token.span),
+            false => Danger(ctx, &rules::DANGEROUS_UNQUOTED_EXPR, expr_str, token.span),
         });
         return results;
     };
     // Technically this is the 'scan_expr' function
     // Expr isn't inherently dangerous, let's check functions inside the expr
-    assert!(token.val.starts_with("{") && token.val.ends_with("}"));
+    assert!(is_braced(token));
     let expr = &token.val[1..token.val.len()-1];
-    let (parse, remaining) = rstcl::parse_expr(expr);
-    assert!(parse.tokens.len() == 1 && remaining == "");
+    let (parse, remaining) = match rstcl::parse_expr_at(expr, inner_start(token)) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            results.push(ParseFailure(ctx, err));
+            return results;
+        },
+    };
+    assert!(parse.tokens.len() == 1 && remaining.is_empty());
     for tok in parse.tokens[0].iter().filter(|tok| tok.ttype == TokenType::Command) {
-        results.extend(scan_command(tok.val).into_iter());
+        results.extend(scan_command_with(tok, options, vars).into_iter());
     }
     return results;
 }
 
+/// The position just after the opening `{`, `[` or `"` of a token, i.e. where
+/// its contents start if they are parsed again
+fn inner_start(token: &rstcl::TclToken) -> Position {
+    let start = token.span.start;
+    return Position { offset: start.offset + 1, col: start.col + 1, ..start };
+}
+
 /// Scans a TokenType::Command token (contained in '[]') for danger
-pub fn scan_command<'a>(string: &'a str) -> Vec<CheckResult<'a>> {
+pub fn scan_command<'a, 'b>(token: &'b rstcl::TclToken<'a>) -> Vec<CheckResult<'a>> {
+    return scan_command_with(token, &ScanOptions::default(), &mut taint::Vars::default());
+}
+
+fn scan_command_with<'a, 'b>(token: &'b rstcl::TclToken<'a>, options: &ScanOptions, vars: &mut taint::Vars) -> Vec<CheckResult<'a>> {
+    let string = token.val;
     assert!(string.starts_with("[") && string.ends_with("]"));
     let script = &string[1..string.len()-1];
-    return scan_script(script);
+    return scan_script_at(script, inner_start(token), options, vars);
+}
+
+/// Options for `scan_script_with`
+#[derive(Clone, Debug, Default)]
+pub struct ScanOptions {
+    /// Report suppression comments which don't suppress anything, see
+    /// `rules::UNUSED_SUPPRESSION`
+    pub report_unused_suppressions: bool,
+    /// Rules not to report findings for
+    pub disabled_rules: Vec<&'static Rule>,
+    /// Severities to report rules at instead of their own (only warn and
+    /// danger can be swapped)
+    pub severities: Vec<(&'static Rule, Severity)>,
+    /// Commands whose results only contain safe characters, as the words
+    /// they start with, e.g. `["string", "length"]`
+    pub safe_commands: Vec<Vec<String>>,
+    /// How commands interpret their arguments, in addition to (or instead
+    /// of) `signatures::Signatures::builtin()`
+    pub signatures: signatures::Signatures,
 }
 
 /// Scans a sequence of commands for danger
+///
+/// Parsing needs no interpreter or other global state, so scripts can be
+/// scanned from any number of threads at once.
+///
+/// ```
+/// use std::thread;
+/// use tclscan::scan_script;
+/// let scans: Vec<_> = (0..4).map(|i| thread::spawn(move || {
+///     let script = format!("set a {}\neval $a", i);
+///     return scan_script(&script).len();
+/// })).collect();
+/// for scan in scans {
+///     assert!(scan.join().unwrap() == 1);
+/// }
+/// ```
+///
+/// Variables which can only hold safe values where they're used, e.g.
+/// because they were set from a number or checked with `string is integer`,
+/// make for warnings rather than danger.
+///
+/// ```
+/// use tclscan::scan_script;
+/// let severities = |script| scan_script(script).iter().map(|r| r.severity().to_string()).collect::<Vec<_>>();
+/// assert!(severities("set n [llength $l]; expr $n+1") == vec!["warn"]);
+/// assert!(severities("set n [llength $l]; set n [gets $f]; expr $n+1") == vec!["danger"]);
+/// // Values are only safe if they can't hold code (or more words, in a script)
+/// assert!(severities("set n {[exec rm -rf /]}; eval $n") == vec!["danger"]);
+/// assert!(severities("set n {[exec rm -rf /]}; expr $n") == vec!["danger"]);
+/// assert!(severities("set n {a b}; expr $n") == vec!["warn"]);
+/// assert!(severities("set n {a b}; eval $n") == vec!["danger"]);
+/// assert!(severities("set n 0; while {1} { expr $n+1; set n $x }") == vec!["danger"]);
+/// assert!(severities("proc p {n} { expr $n+1 }") == vec!["danger"]);
+/// assert!(severities("if {[string is integer -strict $n]} { expr $n+1 }") == vec!["warn"]);
+/// assert!(severities("if {![string is integer -strict $n]} { return }\nexpr $n+1") == vec!["warn"]);
+/// assert!(severities("if {![string is integer -strict $n]} { return } elseif {$a} { set n $x }; expr $n+1") == vec!["danger"]);
+/// assert!(severities("if {![string is integer -strict $n]} { return } else { set n $x }; expr $n+1") == vec!["danger"]);
+/// assert!(severities("if {![string is integer -strict $n]} { return } else { puts $n }; expr $n+1") == vec!["warn"]);
+/// assert!(severities("foreach i {1 2 3} { eval puts $i }") == vec!["warn", "warn"]);
+/// assert!(severities("foreach i {1 2} { set i $x }; eval $i") == vec!["danger"]);
+/// // Scripts which run alongside the command can change variables too
+/// assert!(severities("set n 1; dict for {k v} $d { set n $v }; expr $n+1") == vec!["danger"]);
+/// assert!(severities("set n 1; dict with d { expr $n+1 }") == vec!["danger"]);
+/// assert!(severities("set n 1; lmap v $l { set n $v }; expr $n") == vec!["danger"]);
+/// assert!(severities("set n 1; uplevel 0 {set n $x}; expr $n") == vec!["danger"]);
+/// assert!(severities("set n 1; uplevel 1 {set n $x}; expr $n") == vec!["warn"]);
+/// ```
 pub fn scan_script<'a>(string: &'a str) -> Vec<CheckResult<'a>> {
+    return scan_script_with(string, &ScanOptions::default());
+}
+
+/// Scans a script like `scan_script`, with options
+///
+/// Findings can be suppressed with a `# tclscan: ignore <rule-id> [reason]`
+/// comment before or trailing the command they're in, or a
+/// `# tclscan: ignore-file [reason]` comment anywhere outside a command.
+///
+/// ```
+/// use tclscan::{scan_script, scan_script_with, ScanOptions};
+/// let script = "# tclscan: ignore dangerous-unquoted-block cmd is trusted\neval $cmd\n\
+///               eval $a ;# tclscan: ignore dangerous-unquoted-block\n\
+///               proc p {} {\n  eval $b\n  # tclscan: ignore unquoted-expr\n  eval $c\n}";
+/// let results = scan_script(script);
+/// assert!(results.len() == 2 && results[0].span().start.line == 5 && results[1].span().start.line == 7);
+/// let options = ScanOptions { report_unused_suppressions: true, ..ScanOptions::default() };
+/// let results = scan_script_with(script, &options);
+/// assert!(results.len() == 3 && results[2].rule().id == "unused-suppression");
+/// assert!(results[2].span().start.line == 6 && results[2].span().start.col == 3);
+/// assert!(scan_script("# tclscan: ignore-file generated code\neval $x").len() == 0);
+/// assert!(scan_script("set s {\n# tclscan: ignore-file\n}\neval $x").len() == 1);
+/// ```
+pub fn scan_script_with<'a>(string: &'a str, options: &ScanOptions) -> Vec<CheckResult<'a>> {
+    let mut results = scan_script_at(string, Position::start(), options, &mut taint::Vars::default());
+    if let Some(unused) = suppress::ignore_file(string) {
+        results = if results.len() == 0 { vec![unused] } else { vec![] };
+    }
+    if !options.report_unused_suppressions {
+        results.retain(|result| result.rule() != &rules::UNUSED_SUPPRESSION);
+    }
+    results.retain(|result| !options.disabled_rules.contains(&result.rule()));
+    return results.into_iter().map(|result| {
+        let severity = options.severities.iter()
+            .find(|&&(rule, _)| rule == result.rule())
+            .map(|&(_, severity)| severity);
+        match (result, severity) {
+            (Warn(ctx, rule, code, span), Some(Severity::Danger)) => Danger(ctx, rule, code, span),
+            (Danger(ctx, rule, code, span), Some(Severity::Warn)) => Warn(ctx, rule, code, span),
+            (result, _) => result,
+        }
+    }).collect();
+}
+
+fn scan_script_at<'a>(string: &'a str, pos: Position, options: &ScanOptions, vars: &mut taint::Vars) -> Vec<CheckResult<'a>> {
     let mut all_results: Vec<CheckResult<'a>> = vec![];
-    for parse in rstcl::parse_script(string) {
-        let results = check_command(&parse.command.unwrap(), &parse.tokens);
-        all_results.extend(results.into_iter());
+    // The last command's results are held back in case a comment trailing
+    // it suppresses some of them
+    let mut last: Option<(Vec<CheckResult<'a>>, Vec<suppress::Suppression<'a>>)> = None;
+    let mut last_end = None;
+    for parsed in rstcl::parse_script_recover_at(string, pos) {
+        match parsed {
+            Ok(parse) => {
+                let mut suppressions = vec![];
+                for (suppression, trails) in suppress::parse_comment(string, parse.comment.unwrap(), last_end) {
+                    match last {
+                        Some((_, ref mut last_suppressions)) if trails => last_suppressions.push(suppression),
+                        _ => suppressions.push(suppression),
+                    }
+                }
+                if let Some((results, suppressions)) = last.take() {
+                    all_results.extend(suppress::apply(results, suppressions, string, pos).into_iter());
+                }
+                let command = parse.command.unwrap();
+                last = Some((check_command_with(command, &parse.tokens, options, vars), suppressions));
+                last_end = Some(parse.span.end.offset - pos.offset);
+            },
+            Err((err, skipped)) => {
+                if let Some((results, suppressions)) = last.take() {
+                    all_results.extend(suppress::apply(results, suppressions, string, pos).into_iter());
+                }
+                last_end = None;
+                all_results.push(ParseFailure(skipped.trim(), err));
+            },
+        }
+    }
+    if let Some((results, suppressions)) = last.take() {
+        all_results.extend(suppress::apply(results, suppressions, string, pos).into_iter());
     }
     return all_results;
 }
@@ -1,81 +1,499 @@
-#![feature(plugin)]
+// See lib.rs
+#![allow(clippy::needless_return, clippy::redundant_field_names, clippy::len_zero)]
+#![allow(clippy::match_like_matches_macro, clippy::redundant_static_lifetimes)]
+#![allow(clippy::useless_conversion)]
 
 extern crate rustc_serialize;
 extern crate docopt;
+extern crate globset;
+extern crate walkdir;
 extern crate tclscan;
 
-use std::error::Error;
+use std::collections::HashMap;
 use std::fs;
 use std::io::prelude::*;
 use std::io;
-use std::path::Path;
-use docopt::Docopt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use docopt::{ArgvMap, Docopt};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use walkdir::WalkDir;
 use tclscan::rstcl;
-use tclscan::CheckResult;
+use tclscan::rules::RULES;
+use tclscan::{CheckResult, ScanOptions, Severity};
+use tclscan::baseline::Baseline;
+use tclscan::config::Config;
+use tclscan::report::{Finding, Report};
 
-const USAGE: &'static str = "Usage: tclscan check [--no-warn] ( - | <path> )
-    tclscan parsestr ( - | <script-str> )";
+const USAGE: &'static str = "Usage: tclscan check [--no-warn] [--config=<file> | --no-config] [--report-unused-suppressions] [--jobs=<n>] [--format=<fmt>] [--fail-on=<sev>] [--baseline=<file>] [--write-baseline=<file>] [--ext=<ext>]... [--include=<glob>]... [--exclude=<glob>]... ( - | <path>... )
+    tclscan parsestr ( - | <script-str> )
+    tclscan rules
+    tclscan (-h | --help)
+
+Directories are searched recursively for files with the extensions .tcl, .tm,
+.test, .itcl and .exp. Globs are matched against both the path and file name,
+with paths relative to the directory of the config file for its globs.
+
+Unless --no-config is given, settings are read from the first .tclscan.toml
+found in the directory of the first path or its parents. Options given on the
+command line override it.
+
+Options:
+    -h --help          Show this message
+    --config=<file>    Read settings from this file rather than searching for .tclscan.toml
+    --no-config        Don't read settings from any file
+    --report-unused-suppressions
+                       Report `# tclscan: ignore` comments which don't suppress anything
+    --jobs=<n>         Number of files to scan in parallel (default: number of CPUs)
+    --format=<fmt>     Output format, text, json or sarif (default: text)
+    --fail-on=<sev>    Lowest severity which gives a failing exit code, warn or danger (default: warn)
+    --baseline=<file>  Only report findings which aren't in this baseline, and
+                       list those in it which have since been fixed
+    --write-baseline=<file>
+                       Record the findings in a baseline instead of reporting them
+    --ext=<ext>        Also search directories for files with this extension
+    --include=<glob>   Only scan files matching this glob
+    --exclude=<glob>   Skip files and directories matching this glob
+
+Exit codes:
+    0  Nothing found (at or above the --fail-on severity)
+    1  Warnings found, including code which couldn't be parsed
+    2  Dangers found
+    3  tclscan failed, e.g. a file couldn't be read";
+
+/// Extensions of the files picked up when searching directories
+const EXTENSIONS: &'static [&'static str] = &["tcl", "tm", "test", "itcl", "exp"];
+
+const EXIT_CLEAN: i32 = 0;
+const EXIT_WARN: i32 = 1;
+const EXIT_DANGER: i32 = 2;
+const EXIT_ERROR: i32 = 3;
 
 pub fn main() {
     let args = Docopt::new(USAGE)
                         .and_then(|dopt| dopt.parse())
-                        .unwrap_or_else(|e| e.exit());
+                        .unwrap_or_else(|e| {
+                            // --help isn't a failure
+                            if !e.fatal() {
+                                e.exit();
+                            }
+                            eprintln!("{}", e);
+                            process::exit(EXIT_ERROR);
+                        });
 
     let take_stdin = args.get_bool("-");
     let cmd_check = args.get_bool("check");
     let cmd_parsestr = args.get_bool("parsestr");
+    let cmd_rules = args.get_bool("rules");
+    let arg_script_str = args.get_str("<script-str>");
+
+    let result = match (cmd_check, cmd_parsestr, take_stdin) {
+        _ if cmd_rules => Ok(list_rules()),
+        (true, false, _) => check(&args),
+        (false, true, true) => read_stdin().and_then(|script| parsestr(&script)),
+        (false, true, false) => parsestr(arg_script_str),
+        _ => Err("Internal error: invalid operation".to_owned()),
+    };
+    process::exit(match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{}", err);
+            EXIT_ERROR
+        },
+    });
+}
+
+/// Runs `tclscan check`, returning the exit code
+fn check(args: &ArgvMap) -> Result<i32, String> {
+    let take_stdin = args.get_bool("-");
     let flag_no_warn = args.get_bool("--no-warn");
+    let flag_config = args.get_str("--config");
+    let flag_no_config = args.get_bool("--no-config");
+    let flag_report_unused = args.get_bool("--report-unused-suppressions");
+    let flag_jobs = args.get_str("--jobs");
+    let flag_format = args.get_str("--format");
+    let flag_fail_on = args.get_str("--fail-on");
+    let flag_baseline = args.get_str("--baseline");
+    let flag_write_baseline = args.get_str("--write-baseline");
+    let flag_ext = args.get_vec("--ext");
+    let flag_include = args.get_vec("--include");
+    let flag_exclude = args.get_vec("--exclude");
+    let arg_paths = args.get_vec("<path>");
 
-    let arg_path = args.get_str("<path>");
-    let arg_script_str = args.get_str("<script-str>");
+    let config_path = match (flag_no_config, flag_config) {
+        (true, _) => None,
+        (false, "") => Config::discover(Path::new(arg_paths.first().map_or(".", |path| *path))),
+        (false, path) => Some(PathBuf::from(path)),
+    };
+    let config = match config_path {
+        Some(ref path) => Config::load(path).map_err(|err| format!("ERROR: {}", err))?,
+        None => Config::default(),
+    };
+    let mut options = config.scan_options().map_err(|err| {
+        format!("ERROR: Bad config {}: {}", config_path.as_ref().map_or(String::new(), |path| path.display().to_string()), err)
+    })?;
+    options.report_unused_suppressions = flag_report_unused;
+    // Command line options win over the config file
+    let flag_format = match flag_format {
+        "" => config.format.as_deref().unwrap_or("text"),
+        format => format,
+    };
+    let flag_fail_on = match flag_fail_on {
+        "" => config.fail_on.as_deref().unwrap_or("warn"),
+        fail_on => fail_on,
+    };
+    let mut flag_ext = flag_ext;
+    flag_ext.extend(strs(&config.files.extensions).into_iter());
+    // Globs from the config file are relative to its directory, those on the
+    // command line to the paths as given
+    let (flag_include, include_config) = match flag_include.len() {
+        0 => (strs(&config.files.include), Some(&config)),
+        _ => (flag_include, None),
+    };
+    let (flag_exclude, exclude_config) = match flag_exclude.len() {
+        0 => (strs(&config.files.exclude), Some(&config)),
+        _ => (flag_exclude, None),
+    };
 
-    let script_in = match (cmd_check, cmd_parsestr, take_stdin) {
-        (true, false, false) => {
-            let path = Path::new(&arg_path);
-            let path_display = path.display();
-            let mut file = match fs::File::open(&path) {
-                Err(err) => panic!("ERROR: Couldn't open {}: {}",
-                                   path_display, Error::description(&err)),
-                Ok(file) => file,
-            };
-            let mut file_content = String::new();
-            match file.read_to_string(&mut file_content) {
-                Err(err) => panic!("ERROR: Couldn't read {}: {}",
-                                   path_display, Error::description(&err)),
-                Ok(_) => file_content,
-            }
+    if !["text", "json", "sarif"].contains(&flag_format) {
+        return Err(format!("ERROR: --format must be text, json or sarif, not {}", flag_format));
+    }
+    let text_format = flag_format == "text";
+    let fail_on = match flag_fail_on {
+        "warn" => Severity::Warn,
+        "danger" => Severity::Danger,
+        sev => return Err(format!("ERROR: --fail-on must be warn or danger, not {}", sev)),
+    };
+    let jobs = match flag_jobs {
+        "" => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        n => match n.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => return Err(format!("ERROR: --jobs must be a positive number, not {}", n)),
         },
-        (true, false, true) |
-        (false, true, true) => {
-            let mut stdin_content = String::new();
-            match io::stdin().read_to_string(&mut stdin_content) {
-                Err(err) => panic!("ERROR: Couldn't read stdin: {}",
-                                   Error::description(&err)),
-                Ok(_) => stdin_content,
+    };
+
+    let mut baseline = match flag_baseline {
+        "" => None,
+        path => Some(read_path(path).and_then(|json| {
+            Baseline::from_json(&json).map_err(|err| format!("ERROR: Couldn't load baseline {}: {}", path, err))
+        })?),
+    };
+    let writing_baseline = flag_write_baseline.len() > 0;
+
+    let mut all_findings = vec![];
+    let mut findings = vec![];
+    let mut files_scanned = 0;
+    let mut failed = false;
+    {
+        let mut handle = |scanned: Result<FileScan, String>| match scanned {
+            Ok(scanned) => {
+                files_scanned += 1;
+                let mut text = String::new();
+                for (finding, line) in scanned.findings.into_iter().zip(scanned.lines.into_iter()) {
+                    let known = baseline.as_mut().is_some_and(|baseline| baseline.take(&finding));
+                    if writing_baseline {
+                        all_findings.push(finding);
+                    } else if !known {
+                        text.push_str(&line);
+                        findings.push(finding);
+                    }
+                }
+                if text.len() > 0 && text_format {
+                    output(&format!("{}\n", text));
+                }
+            },
+            // Carry on with the other files, but don't let the run pass
+            Err(err) => {
+                eprintln!("{}", err);
+                failed = true;
+            },
+        };
+        if take_stdin {
+            handle(read_stdin().map(|script| scan_file("<stdin>", &script, &options, flag_no_warn)));
+        } else {
+            let filter = FileFilter::new(&flag_ext, (&flag_include, include_config), (&flag_exclude, exclude_config))?;
+            let files = expand_paths(&arg_paths, &filter)?;
+            let files: Vec<&str> = files.iter().map(|file| &**file).collect();
+            check_paths(&files, jobs, |path, script| scan_file(path, script, &options, flag_no_warn), handle);
+        }
+    }
+    if writing_baseline {
+        // A baseline missing the files which couldn't be read would be wrong
+        if failed {
+            return Ok(EXIT_ERROR);
+        }
+        let json = Baseline::new(&all_findings).to_json();
+        if let Err(err) = fs::write(flag_write_baseline, json + "\n") {
+            return Err(format!("ERROR: Couldn't write baseline {}: {}", flag_write_baseline, err));
+        }
+        eprintln!("Wrote {} findings to {}", all_findings.len(), flag_write_baseline);
+        return Ok(EXIT_CLEAN);
+    }
+
+    let code = if failed { EXIT_ERROR } else { exit_code(&findings, fail_on) };
+    let fixed = baseline.map(|baseline| baseline.remaining()).unwrap_or(vec![]);
+    let report = Report::new(files_scanned, findings).with_fixed(fixed);
+    match flag_format {
+        "json" => output(&format!("{}\n", report.to_json())),
+        "sarif" => output(&format!("{}\n", report.to_sarif())),
+        _ => {
+            if report.fixed.len() > 0 {
+                let mut text = String::from("Fixed since the baseline:\n");
+                for entry in report.fixed.iter() {
+                    let times = if entry.count > 1 { format!(" ({} times)", entry.count) } else { String::new() };
+                    text.push_str(&format!("{}: {} in `{}`{}\n", entry.file, entry.rule, entry.context, times));
+                }
+                output(&text);
             }
         },
-        (false, true, false) => arg_script_str.to_owned(),
-        _ => panic!("Internal error: could not load script"),
+    }
+    return Ok(code);
+}
+
+fn strs(strings: &[String]) -> Vec<&str> {
+    return strings.iter().map(|string| &**string).collect();
+}
+
+/// The exit code for the findings, ignoring any less severe than `fail_on`.
+/// Code which couldn't be parsed might be fine, so it counts as a warning.
+fn exit_code(findings: &[Finding], fail_on: Severity) -> i32 {
+    let worst = findings.iter()
+        .map(|finding| match finding.severity {
+            Severity::Error => Severity::Warn,
+            severity => severity,
+        })
+        .filter(|&severity| severity >= fail_on)
+        .max();
+    return match worst {
+        None => EXIT_CLEAN,
+        Some(Severity::Danger) => EXIT_DANGER,
+        Some(_) => EXIT_WARN,
     };
-    let script = &script_in;
-    match (cmd_check, cmd_parsestr) {
-        (true, false) => {
-            let mut results = tclscan::scan_script(script);
-            if flag_no_warn {
-                results = results.into_iter().filter(|r|
-                    match r { &CheckResult::Warn(_, _, _) => false,  _ => true }
-                ).collect();
+}
+
+/// Writes to stdout, giving up quietly if it's gone (e.g. a closed pipe)
+fn output(text: &str) {
+    if io::stdout().write_all(text.as_bytes()).is_err() {
+        process::exit(EXIT_ERROR);
+    }
+}
+
+fn read_stdin() -> Result<String, String> {
+    let mut stdin_content = vec![];
+    if let Err(err) = io::stdin().read_to_end(&mut stdin_content) {
+        return Err(format!("ERROR: Couldn't read stdin: {}", err));
+    }
+    return decode("stdin", stdin_content);
+}
+
+fn read_path(path_name: &str) -> Result<String, String> {
+    let path = Path::new(path_name);
+    let path_display = path.display();
+    let mut file = match fs::File::open(path) {
+        Err(err) => return Err(format!("ERROR: Couldn't open {}: {}",
+                                       path_display, err)),
+        Ok(file) => file,
+    };
+    let mut file_content = vec![];
+    if let Err(err) = file.read_to_end(&mut file_content) {
+        return Err(format!("ERROR: Couldn't read {}: {}",
+                           path_display, err));
+    }
+    return decode(path_name, file_content);
+}
+
+fn decode(name: &str, content: Vec<u8>) -> Result<String, String> {
+    return String::from_utf8(content).map_err(|err| {
+        format!("ERROR: Couldn't read {}: invalid UTF-8 at byte {}", name, err.utf8_error().valid_up_to())
+    });
+}
+
+/// Which files to scan out of those named on the command line or found in
+/// directories
+struct FileFilter<'c> {
+    extensions: Vec<String>,
+    include: Option<Globs<'c>>,
+    exclude: Globs<'c>,
+}
+impl<'c> FileFilter<'c> {
+    fn new(extensions: &[&str], include: (&[&str], Option<&'c Config>), exclude: (&[&str], Option<&'c Config>)) -> Result<FileFilter<'c>, String> {
+        let mut all_extensions: Vec<String> = EXTENSIONS.iter().map(|ext| (*ext).to_owned()).collect();
+        all_extensions.extend(extensions.iter().map(|ext| ext.trim_start_matches('.').to_owned()));
+        return Ok(FileFilter {
+            extensions: all_extensions,
+            include: if include.0.len() > 0 { Some(Globs::new(include.0, include.1)?) } else { None },
+            exclude: Globs::new(exclude.0, exclude.1)?,
+        });
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        return self.exclude.matches(path);
+    }
+
+    /// Whether to scan a file, `found` if it was found in a directory rather
+    /// than named explicitly
+    fn wants_file(&self, path: &Path, found: bool) -> bool {
+        if found {
+            let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+            if !self.extensions.iter().any(|e| e == ext) {
+                return false;
+            }
+        }
+        if let Some(ref include) = self.include {
+            if !include.matches(path) {
+                return false;
             }
-            if results.len() > 0 {
-                for check_result in results.iter() {
-                    println!("{}", check_result);
+        }
+        return !self.is_excluded(path);
+    }
+}
+
+/// Globs to match paths against, and the config file they came from, if any
+struct Globs<'c> {
+    set: GlobSet,
+    config: Option<&'c Config>,
+}
+impl<'c> Globs<'c> {
+    fn new(globs: &[&str], config: Option<&'c Config>) -> Result<Globs<'c>, String> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs.iter() {
+            builder.add(Glob::new(glob).map_err(|err| format!("ERROR: Bad glob {}: {}", glob, err))?);
+        }
+        let set = builder.build().map_err(|err| format!("ERROR: Bad globs: {}", err))?;
+        return Ok(Globs { set: set, config: config });
+    }
+
+    /// Whether the path or file name matches. Paths are relative to the
+    /// directory of the config file the globs came from, or as given.
+    fn matches(&self, path: &Path) -> bool {
+        let relative = self.config.and_then(|config| config.relative_path(path));
+        let path = relative.as_deref().unwrap_or(path);
+        let path = path.strip_prefix(".").unwrap_or(path);
+        return self.set.is_match(path) || path.file_name().is_some_and(|name| self.set.is_match(name));
+    }
+}
+
+/// The files to scan for the paths given on the command line. Directories
+/// are searched recursively, in order of file name.
+fn expand_paths(paths: &[&str], filter: &FileFilter) -> Result<Vec<String>, String> {
+    let mut files = vec![];
+    for path_name in paths.iter() {
+        let path = Path::new(path_name);
+        // Anything which isn't a directory is left for read_path to complain about
+        if !path.is_dir() {
+            if filter.wants_file(path, false) {
+                files.push((*path_name).to_owned());
+            }
+            continue;
+        }
+        let walker = WalkDir::new(path).sort_by_file_name().into_iter()
+            .filter_entry(|entry| !filter.is_excluded(entry.path()));
+        for entry in walker {
+            let entry = entry.map_err(|err| format!("ERROR: Couldn't search {}: {}", path.display(), err))?;
+            if entry.file_type().is_dir() || !filter.wants_file(entry.path(), true) {
+                continue;
+            }
+            match entry.path().to_str() {
+                Some(file) => files.push(file.to_owned()),
+                None => return Err(format!("ERROR: Path isn't valid UTF-8: {}", entry.path().display())),
+            }
+        }
+    }
+    return Ok(files);
+}
+
+/// Scans the files at `paths` using a pool of `jobs` worker threads. The
+/// results of `scan`, or why a file couldn't be scanned, are passed to
+/// `output` in the order the paths were given, as soon as they're available.
+fn check_paths<T, S, O>(paths: &[&str], jobs: usize, scan: S, mut output: O)
+        where T: Send, S: Fn(&str, &str) -> T + Sync, O: FnMut(Result<T, String>) {
+    let next_path = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(paths.len()) {
+            let tx = tx.clone();
+            let next_path = &next_path;
+            let scan = &scan;
+            scope.spawn(move || {
+                loop {
+                    let i = next_path.fetch_add(1, Ordering::SeqCst);
+                    if i >= paths.len() {
+                        break;
+                    }
+                    // A bug scanning one file shouldn't take down the rest
+                    let result = read_path(paths[i]).and_then(|script| {
+                        panic::catch_unwind(AssertUnwindSafe(|| scan(paths[i], &script)))
+                            .map_err(|_| format!("ERROR: Internal error scanning {}", paths[i]))
+                    });
+                    if tx.send((i, result)).is_err() {
+                        break;
+                    }
                 }
-                println!("");
-            };
-        },
-        (false, true) =>
-            println!("{:?}", rstcl::parse_script(script)),
-        _ =>
-            panic!("Internal error: invalid operation"),
+            });
+        }
+        drop(tx);
+        // Results arrive in any order, hold them back until it's their turn
+        let mut pending = HashMap::new();
+        let mut next_output = 0;
+        for (i, result) in rx {
+            pending.insert(i, result);
+            while let Some(result) = pending.remove(&next_output) {
+                output(result);
+                next_output += 1;
+            }
+        }
+    });
+}
+
+/// Scans a script, leaving out warnings if `no_warn`
+fn scan<'a>(script: &'a str, options: &ScanOptions, no_warn: bool) -> Vec<CheckResult<'a>> {
+    let mut results = tclscan::scan_script_with(script, options);
+    if no_warn {
+        results.retain(|r|
+            match r { &CheckResult::Warn(_, _, _, _) => false,  _ => true }
+        );
+    }
+    return results;
+}
+
+/// What was found in one file
+struct FileScan {
+    findings: Vec<Finding>,
+    /// Each finding as a line of text, see CheckResult's Display
+    lines: Vec<String>,
+}
+
+/// Scans a script from `path_name`
+fn scan_file(path_name: &str, script: &str, options: &ScanOptions, no_warn: bool) -> FileScan {
+    let results = scan(script, options, no_warn);
+    let lines = results.iter()
+        .map(|result| format!("{}:{}: {}\n", path_name, result.span().start, result))
+        .collect();
+    let findings = results.iter().map(|result| Finding::new(path_name, result)).collect();
+    return FileScan { findings: findings, lines: lines };
+}
+
+/// Runs `tclscan parsestr`, returning the exit code, or an error if the
+/// script couldn't be parsed
+fn parsestr(script: &str) -> Result<i32, String> {
+    let parses = match rstcl::parse_script(script) {
+        Ok(parses) => parses,
+        Err(err) => return Err(format!("ERROR: {}", err)),
+    };
+    output(&format!("{:?}\n", parses));
+    return Ok(EXIT_CLEAN);
+}
+
+fn list_rules() -> i32 {
+    let mut text = String::new();
+    for rule in RULES.iter() {
+        let cwe = rule.cwe.map(|cwe| format!(", CWE-{}", cwe)).unwrap_or(String::new());
+        text.push_str(&format!("{} ({}{}): {}\n    {}\n", rule.id, rule.severity, cwe, rule.message, rule.description));
     }
+    output(&text);
+    return EXIT_CLEAN;
 }
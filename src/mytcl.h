@@ -1,2 +0,0 @@
-//#define USE_TCL_STUBS
-#include "/usr/include/tcl/tcl.h"
@@ -0,0 +1,1262 @@
+// A pure Rust implementation of the parsing routines in tclParse.c and
+// tclCompExpr.c. The structure (and the quirks) deliberately follow the C
+// code so that the resulting token trees are identical to those produced by
+// Tcl_ParseCommand and Tcl_ParseExpr.
+
+use rstcl::{Position, Span, TclToken};
+use rstcl::TokenType;
+use rstcl::TokenType::*;
+
+// Character classes, as in tclParse.c
+const TYPE_NORMAL: u8 = 0;
+const TYPE_SPACE: u8 = 0x1;
+const TYPE_COMMAND_END: u8 = 0x2;
+const TYPE_SUBS: u8 = 0x4;
+const TYPE_QUOTE: u8 = 0x8;
+const TYPE_CLOSE_PAREN: u8 = 0x10;
+const TYPE_CLOSE_BRACK: u8 = 0x20;
+const TYPE_BRACE: u8 = 0x40;
+
+// Which substitutions ParseTokens does, as TCL_SUBST_* in tcl.h
+pub const SUBST_COMMANDS: u8 = 0x1;
+pub const SUBST_VARIABLES: u8 = 0x2;
+pub const SUBST_BACKSLASHES: u8 = 0x4;
+pub const SUBST_ALL: u8 = 0x7;
+
+fn char_type(c: u8) -> u8 {
+    return match c {
+        b'\t' | b'\x0b' | b'\x0c' | b'\r' | b' ' => TYPE_SPACE,
+        b'\n' | b';' => TYPE_COMMAND_END,
+        b'\0' | b'$' | b'[' | b'\\' => TYPE_SUBS,
+        b'"' => TYPE_QUOTE,
+        b')' => TYPE_CLOSE_PAREN,
+        b']' => TYPE_CLOSE_BRACK,
+        b'{' | b'}' => TYPE_BRACE,
+        _ => TYPE_NORMAL,
+    };
+}
+
+fn is_bareword(c: u8) -> bool {
+    return c.is_ascii_alphanumeric() || c == b'_';
+}
+
+// TclIsSpaceProc
+fn is_list_space(c: u8) -> bool {
+    return c == b' ' || (b'\t'..=b'\r').contains(&c);
+}
+
+/// A failed parse, with the message Tcl would have left in the interpreter
+/// result and the byte offset (into the string being parsed) at which parsing
+/// stopped.
+#[derive(Debug)]
+pub struct Failure {
+    pub msg: String,
+    pub offset: usize,
+}
+
+fn fail<T>(msg: &str, offset: usize) -> Result<T, Failure> {
+    return Err(Failure { msg: msg.to_owned(), offset: offset });
+}
+
+/// The result of parsing a single command. Positions are byte offsets into
+/// the string being parsed, whereas spans are relative to the original file.
+pub struct CommandParse<'a> {
+    pub comment: Option<(usize, usize)>,
+    pub command_start: usize,
+    pub command_end: usize,
+    pub tokens: Vec<TclToken<'a>>,
+    pub span: Span,
+    term: usize,
+    incomplete: bool,
+}
+
+/// Parses the first command in `string`, which starts at `base` in the
+/// original file.
+pub fn parse_command<'a>(string: &'a str, base: Position) -> Result<CommandParse<'a>, Failure> {
+    let mut parser = Parser { string: string, bytes: string.as_bytes(), incomplete: false };
+    let mut parse = parser.parse_command(0, string.len(), false)?;
+    let index = LineIndex::new(string, parse.command_end, base);
+    index.locate_all(&mut parse.tokens);
+    parse.span = index.span(parse.command_start, parse.command_end);
+    return Ok(parse);
+}
+
+/// Parses `string`, which starts at `base` in the original file, as an
+/// expression. Returns the single SubExpr token (possibly with nested
+/// SubExprs) representing it and the span of the whole expression.
+pub fn parse_expr<'a>(string: &'a str, base: Position) -> Result<(Vec<TclToken<'a>>, Span), Failure> {
+    let mut parser = Parser { string: string, bytes: string.as_bytes(), incomplete: false };
+    let mut tokens = {
+        let mut expr = ExprParser { p: &mut parser, pos: 0, end: string.len() };
+        expr.parse()?
+    };
+    let index = LineIndex::new(string, string.len(), base);
+    index.locate_all(&mut tokens);
+    return Ok((tokens, index.span(0, string.len())));
+}
+
+/// Parses `string`, which starts at `base` in the original file, as the
+/// argument of `subst` doing the substitutions in `flags` (see SUBST_ALL),
+/// returning its tokens and span.
+pub fn parse_subst<'a>(string: &'a str, base: Position, flags: u8) -> Result<(Vec<TclToken<'a>>, Span), Failure> {
+    let mut parser = Parser { string: string, bytes: string.as_bytes(), incomplete: false };
+    let mut tokens = vec![];
+    parser.parse_tokens(0, string.len(), 0, flags, &mut tokens)?;
+    let index = LineIndex::new(string, string.len(), base);
+    index.locate_all(&mut tokens);
+    return Ok((tokens, index.span(0, string.len())));
+}
+
+/// The position of `offset` in `string`, which starts at `base` in the
+/// original file.
+pub fn locate(string: &str, base: Position, offset: usize) -> Position {
+    return LineIndex::new(string, offset, base).position(offset);
+}
+
+// A span which only holds offsets, to be filled in by LineIndex
+fn unlocated(start: usize, end: usize) -> Span {
+    return Span {
+        start: Position { offset: start, line: 0, col: 0 },
+        end: Position { offset: end, line: 0, col: 0 },
+    };
+}
+
+// Converts byte offsets in the string being parsed into positions in the
+// original file. Only the part of the string which has actually been parsed
+// is indexed, so parsing a script command by command stays linear.
+struct LineIndex<'a> {
+    string: &'a str,
+    base: Position,
+    newlines: Vec<usize>,
+    // Columns can be worked out without counting characters
+    ascii: bool,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(string: &'a str, end: usize, base: Position) -> LineIndex<'a> {
+        let newlines = string.as_bytes()[..end].iter().enumerate()
+            .filter(|&(_, &c)| c == b'\n').map(|(i, _)| i).collect();
+        let ascii = string.as_bytes()[..end].is_ascii();
+        return LineIndex { string: string, base: base, newlines: newlines, ascii: ascii };
+    }
+
+    fn position(&self, offset: usize) -> Position {
+        let line = self.newlines.partition_point(|&nl| nl < offset);
+        let (line_start, col) = match line {
+            0 => (0, self.base.col),
+            n => (self.newlines[n - 1] + 1, 1),
+        };
+        return Position {
+            offset: self.base.offset + offset,
+            line: self.base.line + line,
+            col: col + self.chars_between(line_start, offset),
+        };
+    }
+
+    fn chars_between(&self, start: usize, end: usize) -> usize {
+        if self.ascii {
+            return end - start;
+        }
+        // Count characters by skipping UTF-8 continuation bytes
+        return self.string.as_bytes()[start..end].iter().filter(|&&c| c & 0xC0 != 0x80).count();
+    }
+
+    fn span(&self, start: usize, end: usize) -> Span {
+        return Span { start: self.position(start), end: self.position(end) };
+    }
+
+    // Tokens are created with spans holding offsets into the string being
+    // parsed, replace them with full positions
+    fn locate_all(&self, tokens: &mut Vec<TclToken<'a>>) {
+        for token in tokens.iter_mut() {
+            token.span = self.span(token.span.start.offset, token.span.end.offset);
+            self.locate_all(&mut token.tokens);
+        }
+    }
+}
+
+/// TclFindElement: locates the list element starting at or after `start`.
+/// Returns the element start, element end (neither including any enclosing
+/// braces or quotes), the position of the next element and whether the
+/// element is literal (i.e. contains no backslash sequences needing
+/// substitution).
+pub fn find_element(string: &str, start: usize, end: usize) -> Result<(usize, usize, usize, bool), Failure> {
+    let b = string.as_bytes();
+    let mut p = start;
+    let mut open_braces = 0;
+    let mut in_quotes = false;
+    let mut literal = true;
+    while p < end && is_list_space(b[p]) {
+        p += 1;
+    }
+    if p == end {
+        return Ok((end, end, end, true));
+    }
+    if b[p] == b'{' {
+        open_braces = 1;
+        p += 1;
+    } else if b[p] == b'"' {
+        in_quotes = true;
+        p += 1;
+    }
+    let elem_start = p;
+    let mut elem_end = None;
+    while p < end {
+        match b[p] {
+            b'{' => {
+                if open_braces != 0 {
+                    open_braces += 1;
+                }
+            },
+            b'}' => {
+                if open_braces > 1 {
+                    open_braces -= 1;
+                } else if open_braces == 1 {
+                    elem_end = Some(p);
+                    p += 1;
+                    if p >= end || is_list_space(b[p]) {
+                        break;
+                    }
+                    return fail(&garbage_message("braces", string, p, end), p);
+                }
+            },
+            b'\\' => {
+                if open_braces == 0 {
+                    literal = false;
+                }
+                p += backslash_size(b, p, end) - 1;
+            },
+            b'"' => {
+                if in_quotes {
+                    elem_end = Some(p);
+                    p += 1;
+                    if p >= end || is_list_space(b[p]) {
+                        break;
+                    }
+                    return fail(&garbage_message("quotes", string, p, end), p);
+                }
+            },
+            c => {
+                if is_list_space(c) && open_braces == 0 && !in_quotes {
+                    elem_end = Some(p);
+                    break;
+                }
+            },
+        }
+        p += 1;
+    }
+    let elem_end = match elem_end {
+        Some(elem_end) => elem_end,
+        None if open_braces != 0 => return fail("unmatched open brace in list", start),
+        None if in_quotes => return fail("unmatched open quote in list", start),
+        None => p,
+    };
+    while p < end && is_list_space(b[p]) {
+        p += 1;
+    }
+    return Ok((elem_start, elem_end, p, literal));
+}
+
+// The error for a list element followed by something other than a space,
+// showing up to 20 bytes of what's there instead
+fn garbage_message(delimiters: &str, string: &str, start: usize, end: usize) -> String {
+    let b = string.as_bytes();
+    let mut garbage_end = start;
+    while garbage_end < end && !is_list_space(b[garbage_end]) && garbage_end < start + 20 {
+        garbage_end += 1;
+    }
+    while !string.is_char_boundary(garbage_end) {
+        garbage_end -= 1;
+    }
+    return format!("list element in {} followed by \"{}\" instead of space",
+                   delimiters, &string[start..garbage_end]);
+}
+
+/// Tcl_SplitList: splits `string`, which starts at `base` in the original
+/// file, into list elements. Returns the start and end of each element (not
+/// including any enclosing braces or quotes), whether it's literal (see
+/// `find_element`) and its span in the original file.
+pub fn split_list(string: &str, base: Position) -> Result<Vec<(usize, usize, bool, Span)>, Failure> {
+    let index = LineIndex::new(string, string.len(), base);
+    let mut elems = vec![];
+    let mut p = 0;
+    while p < string.len() {
+        let (elem_start, elem_end, next, literal) = find_element(string, p, string.len())?;
+        // Only trailing whitespace was left
+        if elem_start == string.len() {
+            break;
+        }
+        elems.push((elem_start, elem_end, literal, index.span(elem_start, elem_end)));
+        p = next;
+    }
+    return Ok(elems);
+}
+
+/// TclCopyAndCollapse: `string` with its backslash sequences substituted
+pub fn collapse(string: &str) -> String {
+    let b = string.as_bytes();
+    let mut value = String::with_capacity(string.len());
+    let mut p = 0;
+    while p < b.len() {
+        match string[p..].find('\\') {
+            Some(i) => {
+                value.push_str(&string[p..p + i]);
+                let (size, chr) = backslash(b, p + i, b.len());
+                value.push(chr);
+                p += i + size;
+            },
+            None => {
+                value.push_str(&string[p..]);
+                break;
+            },
+        }
+    }
+    return value;
+}
+
+/// TclParseBackslash: the number of bytes making up the backslash sequence
+/// at `pos`.
+pub fn backslash_size(b: &[u8], pos: usize, end: usize) -> usize {
+    return backslash(b, pos, end).0;
+}
+
+/// TclParseBackslash: the number of bytes making up the backslash sequence
+/// at `pos` and the character it stands for.
+pub fn backslash(b: &[u8], pos: usize, end: usize) -> (usize, char) {
+    let num_bytes = end - pos;
+    if num_bytes == 0 {
+        return (0, '\\');
+    }
+    if num_bytes == 1 {
+        return (1, '\\');
+    }
+    let p = pos + 1;
+    let is_octal = |c: u8| (b'0'..b'8').contains(&c);
+    // Characters which can't be represented (e.g. surrogates) become U+FFFD
+    let to_char = |value: u32| char::from_u32(value).unwrap_or('\u{FFFD}');
+    // \x, \u and \U with no hex digits just stand for the letter
+    let hex = |letter: u8, max: usize| match parse_hex(b, p + 1, if num_bytes > max + 1 { max } else { num_bytes - 2 }) {
+        (0, _) => (2, letter as char),
+        (count, value) => (2 + count, to_char(value)),
+    };
+    return match b[p] {
+        b'a' => (2, '\u{7}'),
+        b'b' => (2, '\u{8}'),
+        b'f' => (2, '\u{c}'),
+        b'n' => (2, '\n'),
+        b'r' => (2, '\r'),
+        b't' => (2, '\t'),
+        b'v' => (2, '\u{b}'),
+        b'x' => hex(b'x', 2),
+        b'u' => hex(b'u', 4),
+        b'U' => hex(b'U', 8),
+        b'\n' => {
+            // A backslash-newline and any following spaces become one space
+            let mut count = 2;
+            while count < num_bytes && (b[pos + count] == b' ' || b[pos + count] == b'\t') {
+                count += 1;
+            }
+            (count, ' ')
+        },
+        b'\0' => (1, '\\'),
+        c if is_octal(c) => {
+            let count = if num_bytes == 2 || !is_octal(b[p + 1]) {
+                2
+            } else if num_bytes == 3 || !is_octal(b[p + 2]) || (c - b'0') * 8 + (b[p + 1] - b'0') >= 0x20 {
+                3
+            } else {
+                4
+            };
+            let value = b[p..pos + count].iter().fold(0u32, |value, &c| value * 8 + (c - b'0') as u32);
+            (count, to_char(value & 0xFF))
+        },
+        c => {
+            let len = utf8_len(c);
+            let chr = match ::std::str::from_utf8(&b[p..(p + len).min(end)]) {
+                Ok(string) => string.chars().next().unwrap(),
+                Err(_) => '\u{FFFD}',
+            };
+            (1 + len, chr)
+        },
+    };
+}
+
+// TclParseHex: the number of hex digits consumed and their value
+fn parse_hex(b: &[u8], pos: usize, max: usize) -> (usize, u32) {
+    let mut result: u32 = 0;
+    let mut count = 0;
+    while count < max {
+        let c = b[pos + count];
+        if !c.is_ascii_hexdigit() || result > 0x10FFF {
+            break;
+        }
+        result = (result << 4) + (c as char).to_digit(16).unwrap();
+        count += 1;
+    }
+    return (count, result);
+}
+
+fn utf8_len(first: u8) -> usize {
+    return match first {
+        0xF0..=0xFF => 4,
+        0xE0..=0xEF => 3,
+        0xC0..=0xDF => 2,
+        _ => 1,
+    };
+}
+
+struct Parser<'a> {
+    string: &'a str,
+    bytes: &'a [u8],
+    incomplete: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn token(&self, ttype: TokenType, start: usize, end: usize, tokens: Vec<TclToken<'a>>) -> TclToken<'a> {
+        return TclToken { ttype: ttype, val: &self.string[start..end], tokens: tokens, span: unlocated(start, end) };
+    }
+
+    fn offset(&self, token: &TclToken<'a>) -> usize {
+        return token.val.as_ptr() as usize - self.string.as_ptr() as usize;
+    }
+
+    // Tcl_ParseCommand
+    fn parse_command(&mut self, start: usize, end: usize, nested: bool) -> Result<CommandParse<'a>, Failure> {
+        let b = self.bytes;
+        let terminators = if nested { TYPE_COMMAND_END | TYPE_CLOSE_BRACK } else { TYPE_COMMAND_END };
+
+        let (mut src, comment) = self.parse_comment(start, end);
+        if src == end && nested {
+            self.incomplete = true;
+        }
+        let command_start = src;
+        let mut words = vec![];
+        let term;
+        loop {
+            let (scanned, ctype) = self.parse_white_space(src, end);
+            src += scanned;
+            if src == end {
+                term = src;
+                break;
+            }
+            if ctype & terminators != 0 {
+                term = src;
+                src += 1;
+                break;
+            }
+            let word_start = src;
+            let mut expand_word = false;
+            let mut parts = vec![];
+            loop {
+                if b[src] == b'"' {
+                    src = self.parse_quoted_string(src, end, &mut parts)?;
+                } else if b[src] == b'{' {
+                    src = self.parse_braces(src, end, &mut parts)?;
+                    // Check whether the braces contained the word expansion prefix {*}
+                    if !expand_word && parts.len() == 1 && parts[0].val == "*" && src < end {
+                        let (scanned, ctype) = self.parse_white_space(src, end);
+                        if scanned == 0 && ctype != TYPE_COMMAND_END {
+                            expand_word = true;
+                            parts.clear();
+                            continue;
+                        }
+                    }
+                } else {
+                    src = self.parse_tokens(src, end, TYPE_SPACE | terminators, SUBST_ALL, &mut parts)?;
+                }
+                break;
+            }
+
+            if !expand_word {
+                let ttype = if parts.len() == 1 && parts[0].ttype == Text { SimpleWord } else { Word };
+                words.push(self.token(ttype, word_start, src, parts));
+            } else if parts.iter().all(|tok| tok.ttype == Text) {
+                // An expanded literal is expanded immediately, generating
+                // several SimpleWords rather than a single ExpandWord
+                let list_start = self.offset(&parts[0]);
+                let list_end = self.offset(&parts[parts.len() - 1]) + parts[parts.len() - 1].val.len();
+                match self.expand_literal(list_start, list_end) {
+                    Some(elems) => words.extend(elems.into_iter()),
+                    None => words.push(self.token(ExpandWord, word_start, src, parts)),
+                }
+            } else {
+                words.push(self.token(ExpandWord, word_start, src, parts));
+            }
+
+            // Make sure we're really at the end of a word (there might have
+            // been garbage left after a quoted or braced word), and check
+            // for the end of the command
+            let (scanned, ctype) = self.parse_white_space(src, end);
+            if scanned > 0 {
+                src += scanned;
+                continue;
+            }
+            if src == end {
+                term = src;
+                break;
+            }
+            if ctype & terminators != 0 {
+                term = src;
+                src += 1;
+                break;
+            }
+            if b[src - 1] == b'"' {
+                return fail("extra characters after close-quote", src);
+            } else {
+                return fail("extra characters after close-brace", src);
+            }
+        }
+        return Ok(CommandParse {
+            comment: comment,
+            command_start: command_start,
+            command_end: src,
+            tokens: words,
+            span: unlocated(command_start, src),
+            term: term,
+            incomplete: self.incomplete,
+        });
+    }
+
+    // Splits a literal list being expanded with {*} into SimpleWords.
+    // Returns None if any element would need substitution.
+    fn expand_literal(&self, start: usize, end: usize) -> Option<Vec<TclToken<'a>>> {
+        let b = self.bytes;
+        let mut elems = vec![];
+        let mut next = start;
+        while next < end && is_list_space(b[next]) {
+            next += 1;
+        }
+        while next < end {
+            let word_start = next;
+            let (elem_start, elem_end, elem_next, literal) = match find_element(self.string, next, end) {
+                Ok(elem) => elem,
+                Err(_) => return None,
+            };
+            if !literal {
+                return None;
+            }
+            let quoted = if elem_start > word_start { 1 } else { 0 };
+            let text = self.token(Text, elem_start, elem_end, vec![]);
+            elems.push(self.token(SimpleWord, word_start, elem_end + quoted, vec![text]));
+            next = elem_next;
+        }
+        return Some(elems);
+    }
+
+    // ParseWhiteSpace: returns the number of bytes of whitespace and the type
+    // of the character following it
+    fn parse_white_space(&mut self, start: usize, end: usize) -> (usize, u8) {
+        let b = self.bytes;
+        let mut p = start;
+        let mut ctype = TYPE_NORMAL;
+        loop {
+            while p < end {
+                ctype = char_type(b[p]);
+                if ctype & TYPE_SPACE == 0 {
+                    break;
+                }
+                p += 1;
+            }
+            if p < end && ctype & TYPE_SUBS != 0 {
+                // A backslash-newline is treated just like whitespace
+                if b[p] != b'\\' || p + 1 == end || b[p + 1] != b'\n' {
+                    break;
+                }
+                p += 2;
+                if p == end {
+                    self.incomplete = true;
+                    break;
+                }
+                continue;
+            }
+            break;
+        }
+        return (p - start, ctype);
+    }
+
+    // TclParseAllWhiteSpace: as above, but newlines are also whitespace
+    fn parse_all_white_space(&mut self, start: usize, end: usize) -> usize {
+        let mut p = start;
+        loop {
+            p += self.parse_white_space(p, end).0;
+            if p < end && self.bytes[p] == b'\n' {
+                p += 1;
+                continue;
+            }
+            break;
+        }
+        return p - start;
+    }
+
+    // ParseComment: skips leading whitespace and comments, returning the
+    // position of the start of the command and the span of the comments
+    fn parse_comment(&mut self, start: usize, end: usize) -> (usize, Option<(usize, usize)>) {
+        let b = self.bytes;
+        let mut p = start;
+        let mut incomplete = self.incomplete;
+        let mut comment: Option<(usize, usize)> = None;
+        while p < end {
+            loop {
+                p += self.parse_white_space(p, end).0;
+                if p < end && b[p] == b'\n' {
+                    p += 1;
+                    continue;
+                }
+                break;
+            }
+            if p == end || b[p] != b'#' {
+                break;
+            }
+            let comment_start = match comment {
+                Some((comment_start, _)) => comment_start,
+                None => p,
+            };
+            p += 1;
+            while p < end {
+                if b[p] == b'\n' {
+                    p += 1;
+                    break;
+                }
+                if b[p] == b'\\' {
+                    p += 1;
+                    if p == end {
+                        break;
+                    }
+                }
+                incomplete = b[p] == b'\n';
+                p += 1;
+            }
+            comment = Some((comment_start, p));
+        }
+        self.incomplete = incomplete;
+        return (p, comment);
+    }
+
+    // ParseTokens: parses a run of text, variable, command and backslash
+    // substitutions up to a character matching `mask`, returning the
+    // position of the terminating character. Substitutions not in `flags`
+    // are left as text.
+    fn parse_tokens(&mut self, start: usize, end: usize, mask: u8, flags: u8, acc: &mut Vec<TclToken<'a>>) -> Result<usize, Failure> {
+        let b = self.bytes;
+        let original = acc.len();
+        let mut src = start;
+        while src < end {
+            let ctype = char_type(b[src]);
+            if ctype & mask != 0 {
+                break;
+            }
+            let tok_start = src;
+            if ctype & TYPE_SUBS == 0 {
+                src += 1;
+                while src < end && char_type(b[src]) & (mask | TYPE_SUBS) == 0 {
+                    src += 1;
+                }
+                acc.push(self.token(Text, tok_start, src, vec![]));
+            } else if (b[src] == b'$' && flags & SUBST_VARIABLES == 0) ||
+                    (b[src] == b'[' && flags & SUBST_COMMANDS == 0) ||
+                    (b[src] == b'\\' && flags & SUBST_BACKSLASHES == 0) {
+                src += 1;
+                acc.push(self.token(Text, tok_start, src, vec![]));
+            } else if b[src] == b'$' {
+                let var = self.parse_var_name(src, end)?;
+                src += var.val.len();
+                acc.push(var);
+            } else if b[src] == b'[' {
+                src = self.parse_command_subst(src, end)?;
+                acc.push(self.token(Command, tok_start, src, vec![]));
+            } else if b[src] == b'\\' {
+                let size = backslash_size(b, src, end);
+                if size == 1 {
+                    // Just a backslash, due to end of string
+                    src += 1;
+                    acc.push(self.token(Text, tok_start, src, vec![]));
+                    continue;
+                }
+                if b[src + 1] == b'\n' {
+                    if end - src == 2 {
+                        self.incomplete = true;
+                    }
+                    // A backslash-newline is treated the same as a space
+                    // character would be, so it could terminate the token
+                    if mask & TYPE_SPACE != 0 {
+                        break;
+                    }
+                }
+                src += size;
+                acc.push(self.token(Bs, tok_start, src, vec![]));
+            } else {
+                src += 1;
+                acc.push(self.token(Text, tok_start, src, vec![]));
+            }
+        }
+        if acc.len() == original {
+            // There must always be at least one token, even if empty
+            acc.push(self.token(Text, src, src, vec![]));
+        }
+        return Ok(src);
+    }
+
+    // Parses a command substitution starting at the `[` at `start`,
+    // returning the position after the closing `]`
+    fn parse_command_subst(&mut self, start: usize, end: usize) -> Result<usize, Failure> {
+        let b = self.bytes;
+        let mut src = start + 1;
+        loop {
+            let mut nested = Parser { string: self.string, bytes: b, incomplete: false };
+            let parse = nested.parse_command(src, end, true)?;
+            src = parse.command_end;
+            if parse.term < end && b[parse.term] == b']' && !parse.incomplete {
+                return Ok(src);
+            }
+            if src == end {
+                self.incomplete = true;
+                return fail("missing close-bracket", start);
+            }
+        }
+    }
+
+    // Tcl_ParseVarName: returns either a Variable token or, if the `$` isn't
+    // followed by a variable name, a Text token for the `$`
+    fn parse_var_name(&mut self, start: usize, end: usize) -> Result<TclToken<'a>, Failure> {
+        let b = self.bytes;
+        let mut src = start + 1;
+        if src == end {
+            return Ok(self.token(Text, start, src, vec![]));
+        }
+        let mut subtokens = vec![];
+        if b[src] == b'{' {
+            src += 1;
+            let name_start = src;
+            while src < end && b[src] != b'}' {
+                src += 1;
+            }
+            if src == end {
+                self.incomplete = true;
+                return fail("missing close-brace for variable name", name_start - 1);
+            }
+            subtokens.push(self.token(Text, name_start, src, vec![]));
+            src += 1;
+        } else {
+            let name_start = src;
+            while src < end {
+                if is_bareword(b[src]) {
+                    src += 1;
+                    continue;
+                }
+                if b[src] == b':' && src + 1 < end && b[src + 1] == b':' {
+                    src += 2;
+                    while src < end && b[src] == b':' {
+                        src += 1;
+                    }
+                    continue;
+                }
+                break;
+            }
+            let array = src < end && b[src] == b'(';
+            if src == name_start && !array {
+                return Ok(self.token(Text, start, start + 1, vec![]));
+            }
+            subtokens.push(self.token(Text, name_start, src, vec![]));
+            if array {
+                // The element name can contain any number of substitutions
+                let term = self.parse_tokens(src + 1, end, TYPE_CLOSE_PAREN, SUBST_ALL, &mut subtokens)?;
+                if term == end || b[term] != b')' {
+                    self.incomplete = true;
+                    return fail("missing )", src);
+                }
+                src = term + 1;
+            }
+        }
+        return Ok(self.token(Variable, start, src, subtokens));
+    }
+
+    // Tcl_ParseBraces: returns the position after the closing brace
+    fn parse_braces(&mut self, start: usize, end: usize, acc: &mut Vec<TclToken<'a>>) -> Result<usize, Failure> {
+        let b = self.bytes;
+        let start_index = acc.len();
+        let mut tok_start = start + 1;
+        let mut level = 1;
+        let mut src = start;
+        loop {
+            src += 1;
+            while src < end && char_type(b[src]) == TYPE_NORMAL {
+                src += 1;
+            }
+            if src >= end {
+                self.incomplete = true;
+                return fail("missing close-brace", start);
+            }
+            match b[src] {
+                b'{' => level += 1,
+                b'}' => {
+                    level -= 1;
+                    if level == 0 {
+                        // Emit any partially-finished token, making sure
+                        // there's always a token (even if empty)
+                        if src != tok_start || acc.len() == start_index {
+                            acc.push(self.token(Text, tok_start, src, vec![]));
+                        }
+                        return Ok(src + 1);
+                    }
+                },
+                b'\\' => {
+                    let length = backslash_size(b, src, end);
+                    if length > 1 && b[src + 1] == b'\n' {
+                        // A backslash-newline must be collapsed even inside
+                        // braces, so split the word into multiple tokens
+                        if end - src == 2 {
+                            self.incomplete = true;
+                        }
+                        if src != tok_start {
+                            acc.push(self.token(Text, tok_start, src, vec![]));
+                        }
+                        acc.push(self.token(Bs, src, src + length, vec![]));
+                        tok_start = src + length;
+                    }
+                    src += length - 1;
+                },
+                _ => (),
+            }
+        }
+    }
+
+    // Tcl_ParseQuotedString: returns the position after the closing quote
+    fn parse_quoted_string(&mut self, start: usize, end: usize, acc: &mut Vec<TclToken<'a>>) -> Result<usize, Failure> {
+        let term = self.parse_tokens(start + 1, end, TYPE_QUOTE, SUBST_ALL, acc)?;
+        if term >= end || self.bytes[term] != b'"' {
+            self.incomplete = true;
+            return fail("missing \"", start);
+        }
+        return Ok(term + 1);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Lexeme {
+    Number,
+    Bareword,
+    Script,
+    Braced,
+    Variable,
+    Quoted,
+    Plus,
+    Minus,
+    Not,
+    BitNot,
+    OpenParen,
+    CloseParen,
+    Comma,
+    Question,
+    Colon,
+    // Binary operators other than +/-, with their precedence
+    Binary(u8),
+    Incomplete,
+    Invalid,
+    End,
+}
+use self::Lexeme as L;
+
+// Binary operator precedences, as in tclCompExpr.c
+const PREC_OR: u8 = 1;
+const PREC_AND: u8 = 2;
+const PREC_BIT_OR: u8 = 3;
+const PREC_BIT_XOR: u8 = 4;
+const PREC_BIT_AND: u8 = 5;
+const PREC_EQUAL: u8 = 6;
+const PREC_COMPARE: u8 = 7;
+const PREC_SHIFT: u8 = 8;
+const PREC_ADD: u8 = 9;
+const PREC_MULT: u8 = 10;
+const PREC_EXPON: u8 = 11;
+
+impl Lexeme {
+    fn is_binary(&self) -> bool {
+        return match *self {
+            L::Binary(_) | L::CloseParen | L::Comma | L::Question | L::Colon | L::End => true,
+            _ => false,
+        };
+    }
+}
+
+// A parsed (sub)expression: its extent in the string and the SubExpr tokens
+// it contributes to its parent
+struct Operand<'a> {
+    start: usize,
+    end: usize,
+    tokens: Vec<TclToken<'a>>,
+}
+
+struct ExprParser<'p, 'a: 'p> {
+    p: &'p mut Parser<'a>,
+    pos: usize,
+    end: usize,
+}
+
+impl<'p, 'a> ExprParser<'p, 'a> {
+    fn parse(&mut self) -> Result<Vec<TclToken<'a>>, Failure> {
+        self.skip_white_space();
+        if self.lexeme().0 == L::End {
+            return fail("empty expression", self.pos);
+        }
+        let operand = self.parse_conditional()?;
+        self.skip_white_space();
+        match self.lexeme().0 {
+            L::End => (),
+            L::CloseParen => return fail("unbalanced close paren", self.pos),
+            L::Comma => return fail("unexpected \",\" outside function argument list", self.pos),
+            L::Colon => return fail("unexpected operator \":\" without preceding \"?\"", self.pos),
+            _ => return fail("missing operator", self.pos),
+        }
+        return Ok(operand.tokens);
+    }
+
+    fn skip_white_space(&mut self) {
+        self.pos += self.p.parse_all_white_space(self.pos, self.end);
+    }
+
+    fn lexeme(&self) -> (Lexeme, usize) {
+        return parse_lexeme(self.p.bytes, self.pos, self.end);
+    }
+
+    fn operator(&mut self, len: usize) -> TclToken<'a> {
+        let start = self.pos;
+        self.pos += len;
+        return self.p.token(Operator, start, self.pos, vec![]);
+    }
+
+    fn subexpr(&self, start: usize, end: usize, tokens: Vec<TclToken<'a>>) -> Operand<'a> {
+        return Operand {
+            start: start,
+            end: end,
+            tokens: vec![self.p.token(SubExpr, start, end, tokens)],
+        };
+    }
+
+    // cond ? a : b
+    fn parse_conditional(&mut self) -> Result<Operand<'a>, Failure> {
+        let cond = self.parse_binary(PREC_OR)?;
+        self.skip_white_space();
+        let (lexeme, len) = self.lexeme();
+        if lexeme != L::Question {
+            return Ok(cond);
+        }
+        let op = self.operator(len);
+        let left = self.parse_conditional()?;
+        self.skip_white_space();
+        if self.lexeme().0 != L::Colon {
+            return fail("missing operator \":\"", self.pos);
+        }
+        self.pos += 1;
+        let right = self.parse_conditional()?;
+        let mut tokens = vec![op];
+        tokens.extend(cond.tokens.into_iter());
+        tokens.extend(left.tokens.into_iter());
+        tokens.extend(right.tokens.into_iter());
+        return Ok(self.subexpr(cond.start, right.end, tokens));
+    }
+
+    fn parse_binary(&mut self, min_prec: u8) -> Result<Operand<'a>, Failure> {
+        let mut left = self.parse_unary()?;
+        loop {
+            self.skip_white_space();
+            let (lexeme, len) = self.lexeme();
+            let prec = match lexeme {
+                L::Plus | L::Minus => PREC_ADD,
+                L::Binary(prec) => prec,
+                _ => break,
+            };
+            if prec < min_prec {
+                break;
+            }
+            let op = self.operator(len);
+            // Exponentiation is right associative
+            let right = self.parse_binary(if prec == PREC_EXPON { prec } else { prec + 1 })?;
+            let mut tokens = vec![op];
+            tokens.extend(left.tokens.into_iter());
+            tokens.extend(right.tokens.into_iter());
+            left = self.subexpr(left.start, right.end, tokens);
+        }
+        return Ok(left);
+    }
+
+    fn parse_unary(&mut self) -> Result<Operand<'a>, Failure> {
+        self.skip_white_space();
+        let start = self.pos;
+        let (lexeme, len) = self.lexeme();
+        return match lexeme {
+            L::Plus | L::Minus | L::Not | L::BitNot => {
+                let op = self.operator(len);
+                let operand = self.parse_unary()?;
+                let mut tokens = vec![op];
+                tokens.extend(operand.tokens.into_iter());
+                Ok(self.subexpr(start, operand.end, tokens))
+            },
+            L::OpenParen => {
+                // Parentheses don't generate any tokens of their own
+                self.pos += len;
+                self.skip_white_space();
+                if self.lexeme().0 == L::CloseParen {
+                    return fail("empty subexpression", self.pos);
+                }
+                let inner = self.parse_conditional()?;
+                self.expect_close_paren(start)?;
+                Ok(Operand { start: start, end: self.pos, tokens: inner.tokens })
+            },
+            L::Number => {
+                self.pos += len;
+                let text = self.p.token(Text, start, self.pos, vec![]);
+                Ok(self.subexpr(start, self.pos, vec![text]))
+            },
+            L::Bareword => {
+                let name_end = start + len;
+                let after = name_end + self.p.parse_all_white_space(name_end, self.end);
+                if after < self.end && self.p.bytes[after] == b'(' {
+                    self.parse_function(len, after)
+                } else if is_boolean(&self.p.string[start..name_end]) {
+                    self.pos += len;
+                    let text = self.p.token(Text, start, self.pos, vec![]);
+                    Ok(self.subexpr(start, self.pos, vec![text]))
+                } else {
+                    fail(&format!("invalid bareword \"{}\"", &self.p.string[start..name_end]), start)
+                }
+            },
+            L::Quoted | L::Braced | L::Variable | L::Script => self.parse_word(lexeme),
+            L::Invalid => fail(&format!("invalid character \"{}\"", &self.p.string[start..start+len]), start),
+            L::Incomplete => fail(&format!("incomplete operator \"{}\"", &self.p.string[start..start+len]), start),
+            L::Colon | L::Comma | L::CloseParen | L::Question | L::Binary(_) | L::End => {
+                fail("missing operand", start)
+            },
+        };
+    }
+
+    fn expect_close_paren(&mut self, open: usize) -> Result<(), Failure> {
+        self.skip_white_space();
+        return match self.lexeme().0 {
+            L::CloseParen => {
+                self.pos += 1;
+                Ok(())
+            },
+            L::End => fail("unbalanced open paren", open),
+            L::Comma => fail("unexpected \",\" outside function argument list", self.pos),
+            L::Colon => fail("unexpected operator \":\" without preceding \"?\"", self.pos),
+            _ => fail("missing operator", self.pos),
+        };
+    }
+
+    // name(arg, ...)
+    fn parse_function(&mut self, name_len: usize, open: usize) -> Result<Operand<'a>, Failure> {
+        let start = self.pos;
+        let mut tokens = vec![self.operator(name_len)];
+        self.pos = open + 1;
+        self.skip_white_space();
+        if self.lexeme().0 == L::CloseParen {
+            self.pos += 1;
+            return Ok(self.subexpr(start, self.pos, tokens));
+        }
+        loop {
+            let arg = self.parse_conditional()?;
+            tokens.extend(arg.tokens.into_iter());
+            self.skip_white_space();
+            match self.lexeme().0 {
+                L::Comma => self.pos += 1,
+                L::CloseParen => {
+                    self.pos += 1;
+                    break;
+                },
+                L::End => return fail("missing close paren to end function arguments", open),
+                L::Colon => return fail("unexpected operator \":\" without preceding \"?\"", self.pos),
+                _ => return fail("missing operator", self.pos),
+            }
+        }
+        return Ok(self.subexpr(start, self.pos, tokens));
+    }
+
+    // A leaf which is parsed as a Tcl word
+    fn parse_word(&mut self, lexeme: Lexeme) -> Result<Operand<'a>, Failure> {
+        let start = self.pos;
+        let mut parts = vec![];
+        self.pos = match lexeme {
+            L::Quoted => self.p.parse_quoted_string(start, self.end, &mut parts)?,
+            L::Braced => self.p.parse_braces(start, self.end, &mut parts)?,
+            L::Variable => {
+                let var = self.p.parse_var_name(start, self.end)?;
+                if var.ttype != Variable {
+                    return fail("invalid character \"$\"", start);
+                }
+                parts.push(var);
+                start + parts[0].val.len()
+            },
+            L::Script => {
+                let end = self.p.parse_command_subst(start, self.end)?;
+                parts.push(self.p.token(Command, start, end, vec![]));
+                end
+            },
+            _ => panic!("Not a word lexeme {:?}", lexeme),
+        };
+        // A single element word is represented directly as a SubExpr, but
+        // multiple elements are kept grouped in a Word
+        if parts.len() == 1 {
+            return Ok(self.subexpr(start, self.pos, parts));
+        }
+        let word = self.p.token(Word, start, self.pos, parts);
+        return Ok(self.subexpr(start, self.pos, vec![word]));
+    }
+}
+
+// Tcl_GetBoolean, for the barewords it accepts
+fn is_boolean(word: &str) -> bool {
+    let word = word.to_ascii_lowercase();
+    if word.len() == 0 {
+        return false;
+    }
+    let prefix_of = |full: &str, min: usize| word.len() >= min && full.starts_with(&word[..]);
+    return prefix_of("true", 1) || prefix_of("false", 1) || prefix_of("yes", 1) ||
+        prefix_of("no", 1) || word == "on" || prefix_of("off", 2);
+}
+
+// ParseLexeme: returns the lexeme at `start` and its length
+fn parse_lexeme(b: &[u8], start: usize, end: usize) -> (Lexeme, usize) {
+    if start == end {
+        return (L::End, 0);
+    }
+    let next = if start + 1 < end { b[start + 1] } else { b'\0' };
+    // Word operators, which mustn't be followed by another letter
+    let word_op = start + 2 >= end || b[start + 2] & 0x80 != 0 || !b[start + 2].is_ascii_alphabetic();
+    match b[start] {
+        b'"' => return (L::Quoted, 1),
+        b'$' => return (L::Variable, 1),
+        b'(' => return (L::OpenParen, 1),
+        b')' => return (L::CloseParen, 1),
+        b'+' => return (L::Plus, 1),
+        b',' => return (L::Comma, 1),
+        b'-' => return (L::Minus, 1),
+        b'%' | b'/' => return (L::Binary(PREC_MULT), 1),
+        b':' => return (L::Colon, 1),
+        b'?' => return (L::Question, 1),
+        b'[' => return (L::Script, 1),
+        b'^' => return (L::Binary(PREC_BIT_XOR), 1),
+        b'{' => return (L::Braced, 1),
+        b'~' => return (L::BitNot, 1),
+        b'*' if next == b'*' => return (L::Binary(PREC_EXPON), 2),
+        b'*' => return (L::Binary(PREC_MULT), 1),
+        b'=' if next == b'=' => return (L::Binary(PREC_EQUAL), 2),
+        b'=' => return (L::Incomplete, 1),
+        b'!' if next == b'=' => return (L::Binary(PREC_EQUAL), 2),
+        b'!' => return (L::Not, 1),
+        b'&' if next == b'&' => return (L::Binary(PREC_AND), 2),
+        b'&' => return (L::Binary(PREC_BIT_AND), 1),
+        b'|' if next == b'|' => return (L::Binary(PREC_OR), 2),
+        b'|' => return (L::Binary(PREC_BIT_OR), 1),
+        b'<' if next == b'<' => return (L::Binary(PREC_SHIFT), 2),
+        b'<' if next == b'=' => return (L::Binary(PREC_COMPARE), 2),
+        b'<' => return (L::Binary(PREC_COMPARE), 1),
+        b'>' if next == b'>' => return (L::Binary(PREC_SHIFT), 2),
+        b'>' if next == b'=' => return (L::Binary(PREC_COMPARE), 2),
+        b'>' => return (L::Binary(PREC_COMPARE), 1),
+        b'i' if next == b'n' && word_op => return (L::Binary(PREC_EQUAL), 2),
+        b'e' if next == b'q' && word_op => return (L::Binary(PREC_EQUAL), 2),
+        b'n' if (next == b'e' || next == b'i') && word_op => return (L::Binary(PREC_EQUAL), 2),
+        c if c < 0x80 && !is_bareword(c) && c != b'.' => return (L::Invalid, 1),
+        _ => (),
+    }
+
+    if let Some((num_end, is_double)) = scan_number(b, start, end) {
+        if num_end < end && !is_bareword(b[num_end]) {
+            return (L::Number, num_end - start);
+        }
+        // A number followed directly by bareword characters is either a
+        // number followed by a word operator, or a single bareword
+        if is_double && !(start..num_end).all(|i| is_bareword(b[i])) {
+            return (L::Number, num_end - start);
+        }
+        if parse_lexeme(b, num_end, end).0.is_binary() {
+            return (L::Number, num_end - start);
+        }
+    }
+
+    if !is_bareword(b[start]) || b[start] == b'_' {
+        return (L::Invalid, utf8_len(b[start]).min(end - start));
+    }
+    let mut word_end = start;
+    while word_end < end && is_bareword(b[word_end]) {
+        word_end += 1;
+    }
+    return (L::Bareword, word_end - start);
+}
+
+// TclParseNumber: returns the end of the longest prefix of the string which
+// is a valid number, and whether that number is a floating point value
+fn scan_number(b: &[u8], start: usize, end: usize) -> Option<(usize, bool)> {
+    let at = |i: usize| if i < end { b[i] } else { b'\0' };
+    let digits = |mut i: usize, radix: u32| {
+        while i < end && (b[i] as char).is_digit(radix) {
+            i += 1;
+        }
+        i
+    };
+    let lower = |i: usize| at(i).to_ascii_lowercase();
+
+    // Infinity and NaN
+    if lower(start) == b'i' && lower(start + 1) == b'n' && lower(start + 2) == b'f' {
+        let rest = b"inity";
+        if (0..rest.len()).all(|i| lower(start + 3 + i) == rest[i]) {
+            return Some((start + 8, true));
+        }
+        return Some((start + 3, true));
+    }
+    if lower(start) == b'n' && lower(start + 1) == b'a' && lower(start + 2) == b'n' {
+        return Some((start + 3, true));
+    }
+
+    // Prefixed integers
+    if at(start) == b'0' {
+        let radix = match lower(start + 1) {
+            b'x' => 16,
+            b'o' => 8,
+            b'b' => 2,
+            _ => 0,
+        };
+        if radix != 0 {
+            let num_end = digits(start + 2, radix);
+            if num_end == start + 2 {
+                return Some((start + 1, false));
+            }
+            return Some((num_end, false));
+        }
+    }
+
+    // Decimal integers and floating point values
+    let int_end = digits(start, 10);
+    let mut num_end = int_end;
+    let mut is_double = false;
+    if at(num_end) == b'.' {
+        let frac_end = digits(num_end + 1, 10);
+        if int_end > start || frac_end > num_end + 1 {
+            num_end = frac_end;
+            is_double = true;
+        }
+    }
+    if num_end == start {
+        return None;
+    }
+    if lower(num_end) == b'e' {
+        let mut exp_start = num_end + 1;
+        if at(exp_start) == b'+' || at(exp_start) == b'-' {
+            exp_start += 1;
+        }
+        let exp_end = digits(exp_start, 10);
+        if exp_end > exp_start {
+            num_end = exp_end;
+            is_double = true;
+        }
+    }
+    // Leading zeros introduce an octal integer, which is only valid as a
+    // whole if it's really the integer part of a floating point value
+    if !is_double && at(start) == b'0' {
+        let octal_end = digits(start, 8);
+        if octal_end < int_end {
+            return Some((octal_end, false));
+        }
+    }
+    return Some((num_end, is_double));
+}
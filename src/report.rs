@@ -0,0 +1,233 @@
+//! Machine readable reports of the results of `scan_script`, so they can be
+//! consumed by other tools without scraping the text output.
+
+use serde::Serialize;
+use serde_json::{self, Value};
+use baseline::Entry;
+use rstcl::Position;
+use rules::RULES;
+use {CheckResult, Severity};
+
+/// A `CheckResult` from a particular file, which doesn't borrow from the
+/// scanned script
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Finding {
+    pub file: String,
+    /// The id of the rule broken
+    pub rule: String,
+    pub severity: Severity,
+    pub message: String,
+    /// Why the code couldn't be parsed, for parse failures
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// The problem code, absent for parse failures
+    pub token: Option<String>,
+    /// The command containing the problem code
+    pub context: String,
+    pub start: Position,
+    pub end: Position,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwe: Option<u32>,
+}
+impl Finding {
+    pub fn new(file: &str, result: &CheckResult) -> Finding {
+        let (message, detail, token, context) = match *result {
+            CheckResult::Warn(ctx, rule, token, _) |
+            CheckResult::Danger(ctx, rule, token, _) => (rule.message, None, Some(token.to_owned()), ctx),
+            CheckResult::ParseFailure(ctx, ref err) => (err.message, Some(err.result.clone()), None, ctx),
+        };
+        let span = result.span();
+        let rule = result.rule();
+        return Finding {
+            file: file.to_owned(),
+            rule: rule.id.to_owned(),
+            severity: result.severity(),
+            message: message.to_owned(),
+            detail: detail,
+            token: token,
+            context: context.to_owned(),
+            start: span.start,
+            end: span.end,
+            cwe: rule.cwe,
+        };
+    }
+}
+
+/// Counts of the findings in a report
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    /// Number of files scanned
+    pub files: usize,
+    pub findings: usize,
+    pub warn: usize,
+    pub danger: usize,
+    pub error: usize,
+    /// Number of baseline entries which no longer match any findings
+    pub fixed: usize,
+}
+
+/// All the findings from a run of `tclscan check`
+///
+/// ```
+/// extern crate serde_json;
+/// extern crate tclscan;
+/// use tclscan::report::{Finding,Report};
+/// use tclscan::scan_script;
+/// # fn main() {
+/// let findings = scan_script("eval $x\nif [y] {}").iter()
+///     .map(|result| Finding::new("a.tcl", result)).collect();
+/// let report = Report::new(1, findings);
+/// let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+/// assert!(json["summary"]["danger"] == 2 && json["summary"]["files"] == 1);
+/// let finding = &json["findings"][1];
+/// assert!(finding["severity"] == "danger" && finding["token"] == "[y]");
+/// assert!(finding["rule"] == "dangerous-unquoted-expr" && finding["cwe"] == 95);
+/// assert!(finding["context"] == "if [y] {}" && finding["file"] == "a.tcl");
+/// assert!(finding["start"]["line"] == 2 && finding["start"]["col"] == 4);
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+    /// Findings in the baseline which weren't found, if there is one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fixed: Vec<Entry>,
+    pub summary: Summary,
+}
+impl Report {
+    pub fn new(files: usize, findings: Vec<Finding>) -> Report {
+        let count = |severity| findings.iter().filter(|f| f.severity == severity).count();
+        let summary = Summary {
+            files: files,
+            findings: findings.len(),
+            warn: count(Severity::Warn),
+            danger: count(Severity::Danger),
+            error: count(Severity::Error),
+            fixed: 0,
+        };
+        return Report { findings: findings, fixed: vec![], summary: summary };
+    }
+
+    /// Adds the baseline entries which have since been fixed
+    pub fn with_fixed(mut self, fixed: Vec<Entry>) -> Report {
+        self.summary.fixed = fixed.iter().map(|entry| entry.count).sum();
+        self.fixed = fixed;
+        return self;
+    }
+
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string_pretty(self).expect("report couldn't be serialized");
+    }
+
+    /// The report as a SARIF 2.1.0 log, for code scanning tools
+    ///
+    /// ```
+    /// extern crate serde_json;
+    /// extern crate tclscan;
+    /// use tclscan::report::{Finding,Report};
+    /// use tclscan::scan_script;
+    /// # fn main() {
+    /// let findings = scan_script("eval $x\nif [info exists y] {}\neval $z").iter()
+    ///     .map(|result| Finding::new("lib/a b.tcl", result)).collect();
+    /// let sarif: serde_json::Value = serde_json::from_str(&Report::new(1, findings).to_sarif()).unwrap();
+    /// assert!(sarif["version"] == "2.1.0");
+    /// let run = &sarif["runs"][0];
+    /// let rules = run["tool"]["driver"]["rules"].as_array().unwrap();
+    /// assert!(rules[0]["id"] == "dangerous-unquoted-block" && rules[0]["properties"]["cwe"] == "CWE-95");
+    /// let result = &run["results"][1];
+    /// assert!(result["ruleId"] == "unquoted-expr" && result["level"] == "warning");
+    /// assert!(rules[result["ruleIndex"].as_u64().unwrap() as usize]["id"] == "unquoted-expr");
+    /// let location = &result["locations"][0]["physicalLocation"];
+    /// assert!(location["artifactLocation"]["uri"] == "lib/a%20b.tcl");
+    /// assert!(location["region"]["startLine"] == 2 && location["region"]["startColumn"] == 4);
+    /// assert!(location["region"]["snippet"]["text"] == "[info exists y]");
+    /// # }
+    /// ```
+    pub fn to_sarif(&self) -> String {
+        let rules: Vec<Value> = RULES.iter().map(|rule| {
+            let mut descriptor = json!({
+                "id": rule.id,
+                "shortDescription": { "text": rule.message },
+                "fullDescription": { "text": rule.description },
+                "defaultConfiguration": { "level": sarif_level(rule.severity) },
+            });
+            if let Some(cwe) = rule.cwe {
+                descriptor["properties"] = json!({
+                    "cwe": format!("CWE-{}", cwe),
+                    "tags": ["security", format!("external/cwe/cwe-{}", cwe)],
+                });
+            }
+            descriptor
+        }).collect();
+        let mut results = vec![];
+        for finding in self.findings.iter() {
+            let rule_index = RULES.iter().position(|rule| rule.id == finding.rule);
+            let text = match (&finding.token, &finding.detail) {
+                (Some(token), _) => format!("{} at `{}` in `{}`", finding.message, token, finding.context),
+                (None, Some(detail)) => format!("{} ({}) in `{}`", finding.message, detail, finding.context),
+                (None, None) => format!("{} in `{}`", finding.message, finding.context),
+            };
+            let mut region = json!({
+                "startLine": finding.start.line,
+                "startColumn": finding.start.col,
+                "endLine": finding.end.line,
+                "endColumn": finding.end.col,
+            });
+            if let Some(ref token) = finding.token {
+                region["snippet"] = json!({ "text": token });
+            }
+            results.push(json!({
+                "ruleId": finding.rule,
+                "ruleIndex": rule_index,
+                "level": sarif_level(finding.severity),
+                "message": { "text": text },
+                "locations": [{
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": path_uri(&finding.file) },
+                        "region": region,
+                    },
+                }],
+            }));
+        }
+        let log = json!({
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": "tclscan",
+                        "informationUri": "https://github.com/aidanhs/tclscan",
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "rules": Value::Array(rules),
+                    },
+                },
+                // Columns count characters, not UTF-16 code units
+                "columnKind": "unicodeCodePoints",
+                "results": Value::Array(results),
+            }],
+        });
+        return serde_json::to_string_pretty(&log).expect("report couldn't be serialized");
+    }
+}
+
+fn sarif_level(severity: Severity) -> &'static str {
+    return match severity {
+        Severity::Warn => "warning",
+        Severity::Danger | Severity::Error => "error",
+    };
+}
+
+// A URI for a path, as SARIF wants. Relative paths stay relative.
+fn path_uri(path: &str) -> String {
+    let mut uri = String::new();
+    if path.starts_with("/") {
+        uri.push_str("file://");
+    }
+    for b in path.replace('\\', "/").bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(b as char),
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    return uri;
+}
@@ -1,21 +1,11 @@
-use std::mem::uninitialized;
-use std::ffi::CString;
+use std::borrow::Cow;
+use std::fmt;
 
-use num::traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
 
-use tcl;
-use self::TokenType::*;
+use parser;
 
-static mut I: Option<*mut tcl::Tcl_Interp> = None;
-unsafe fn tcl_interp() -> *mut tcl::Tcl_Interp {
-    if I.is_none() {
-        I = Some(tcl::Tcl_CreateInterp());
-    }
-    return I.unwrap();
-}
-
-enum_from_primitive! {
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum TokenType {
     Word = 1, // TCL_TOKEN_WORD
     SimpleWord = 2, // TCL_TOKEN_SIMPLE_WORD
@@ -27,6 +17,52 @@ pub enum TokenType {
     Operator = 128, // TCL_TOKEN_OPERATOR
     ExpandWord = 256, // TCL_TOKEN_EXPAND_WORD
 }
+
+/// A location in the original source: a byte offset, plus the line and the
+/// column (counted in characters), both starting from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub col: usize,
+}
+impl Position {
+    /// The position of the start of a file
+    pub fn start() -> Position {
+        return Position { offset: 0, line: 1, col: 1 };
+    }
+}
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}:{}", self.line, self.col);
+    }
+}
+/// A command or expression which couldn't be parsed
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// What was being parsed, e.g. "couldn't parse command"
+    pub message: &'static str,
+    /// Where parsing stopped in the original source
+    pub position: Position,
+    /// The error Tcl leaves in the interpreter result, e.g. "missing close-brace"
+    pub result: String,
+}
+impl ParseError {
+    /// The byte offset in the original source at which parsing stopped
+    pub fn offset(&self) -> usize {
+        return self.position.offset;
+    }
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{} at {}: {}", self.message, self.position, self.result);
+    }
+}
+/// The extent of a token or command in the original source, end exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
 }
 
 #[derive(Debug, PartialEq)]
@@ -34,174 +70,436 @@ pub struct TclParse<'a> {
     pub comment: Option<&'a str>,
     pub command: Option<&'a str>,
     pub tokens: Vec<TclToken<'a>>,
+    pub span: Span,
 }
 #[derive(Debug, PartialEq)]
 pub struct TclToken<'a> {
     pub ttype: TokenType,
     pub val: &'a str,
     pub tokens: Vec<TclToken<'a>>,
+    pub span: Span,
+}
+impl<'a> TclParse<'a> {
+    /// Copies the parse so it no longer borrows from the script
+    pub fn to_owned(&self) -> OwnedTclParse {
+        return OwnedTclParse {
+            comment: self.comment.map(|s| s.to_owned()),
+            command: self.command.map(|s| s.to_owned()),
+            tokens: self.tokens.iter().map(|tok| tok.to_owned()).collect(),
+            span: self.span,
+        };
+    }
+}
+/// A `TclParse` which owns its strings, so it can be kept, sent between
+/// threads and (de)serialized.
+///
+/// ```
+/// extern crate serde_json;
+/// extern crate tclscan;
+/// use tclscan::rstcl::{parse_command,OwnedTclParse};
+/// # fn main() {
+/// let parse = parse_command("puts [a $b]").unwrap().0.to_owned();
+/// let json = serde_json::to_string(&parse).unwrap();
+/// assert!(serde_json::from_str::<OwnedTclParse>(&json).unwrap() == parse);
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OwnedTclParse {
+    pub comment: Option<String>,
+    pub command: Option<String>,
+    pub tokens: Vec<OwnedTclToken>,
+    pub span: Span,
+}
+/// A `TclToken` which owns its strings, see `OwnedTclParse`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OwnedTclToken {
+    pub ttype: TokenType,
+    pub val: String,
+    pub tokens: Vec<OwnedTclToken>,
+    pub span: Span,
 }
 impl<'b> TclToken<'b> {
+    /// Copies the token so it no longer borrows from the script
+    pub fn to_owned(&self) -> OwnedTclToken {
+        return OwnedTclToken {
+            ttype: self.ttype,
+            val: self.val.to_owned(),
+            tokens: self.tokens.iter().map(|tok| tok.to_owned()).collect(),
+            span: self.span,
+        };
+    }
+    /// The value of the token after substitution, or None if that depends
+    /// on variable or command substitutions (or if the token is an expanded
+    /// word or part of an expr). Backslash sequences are decoded and braces
+    /// are removed.
+    ///
+    /// ```
+    /// use tclscan::rstcl::parse_command;
+    /// let (parse, _) = parse_command("\\x65val {a \\n b} \"x\\ty\" a$b").unwrap();
+    /// let values: Vec<_> = parse.tokens.iter().map(|tok| tok.literal()).collect();
+    /// assert!(values == vec![Some("eval".into()), Some("a \\n b".into()), Some("x\ty".into()), None]);
+    /// ```
+    pub fn literal(&self) -> Option<Cow<'b, str>> {
+        return match self.ttype {
+            TokenType::Text => Some(Cow::Borrowed(self.val)),
+            TokenType::Bs => {
+                let (_, chr) = parser::backslash(self.val.as_bytes(), 0, self.val.len());
+                Some(Cow::Owned(chr.to_string()))
+            },
+            TokenType::SimpleWord => self.tokens[0].literal(),
+            TokenType::Word => {
+                let mut value = String::new();
+                for token in self.tokens.iter() {
+                    value.push_str(&token.literal()?);
+                }
+                Some(Cow::Owned(value))
+            },
+            TokenType::Variable | TokenType::Command | TokenType::ExpandWord |
+            TokenType::SubExpr | TokenType::Operator => None,
+        };
+    }
+    /// Iterates over this token and all the tokens inside it, in pre-order
     pub fn iter<'a>(&'a self) -> TclTokenIter<'a, 'b> {
         TclTokenIter {
-            token: self,
-            cur: 0,
+            stack: vec![self],
         }
     }
-    fn traverse(&self, num: usize) -> (usize, Option<&TclToken<'b>>) {
-        if num == 0 {
-            return (0, Some(self));
-        }
-        let mut numleft = num - 1;
-        for subtok in self.tokens.iter() {
-            match subtok.traverse(numleft) {
-                (0, Some(tok)) => { return (0, Some(tok)); },
-                (n, None) => { numleft = n; },
-                _ => assert!(false),
-            }
-        }
-        return (numleft, None);
-    }
 }
 pub struct TclTokenIter<'a, 'b: 'a> {
-    token: &'a TclToken<'b>,
-    cur: usize,
+    // Tokens still to visit, the next one last
+    stack: Vec<&'a TclToken<'b>>,
 }
 impl<'b, 'c: 'b> Iterator for TclTokenIter<'b, 'c> {
     type Item = &'b TclToken<'c>;
     fn next(&mut self) -> Option<&'b TclToken<'c>> {
-        self.cur += 1;
-        let ret: Option<&'b TclToken<'c>> = match self.token.traverse(self.cur-1) {
-            (0, Some(tok)) => Some(tok),
-            (0, None) => None,
-            x => panic!("Invalid traverse return {:?}, iterator called after finish?", x),
-        };
-        return ret;
+        let token = self.stack.pop()?;
+        self.stack.extend(token.tokens.iter().rev());
+        return Some(token);
     }
 }
 
 /// Takes: a string, which should be a tcl script
-/// Returns: a parse structure and the remaining string.
+/// Returns: a parse structure and the remaining string, or why the first
+/// command couldn't be parsed.
 ///
 /// ```
 /// use tclscan::rstcl::{TclParse,TclToken};
 /// use tclscan::rstcl::TokenType::{SimpleWord,Word,Variable,Text,Command};
 /// use tclscan::rstcl::parse_command;
-/// assert!(parse_command("a b $c [d]") == (TclParse {
-///     comment: Some(""), command: Some("a b $c [d]"),
+/// use tclscan::rstcl::{Position,Span};
+/// fn p(offset: usize, line: usize, col: usize) -> Position {
+///     return Position { offset: offset, line: line, col: col };
+/// }
+/// // A span on the first line
+/// fn s(start: usize, end: usize) -> Span {
+///     return Span { start: p(start, 1, start + 1), end: p(end, 1, end + 1) };
+/// }
+/// assert!(parse_command("a b $c [d]") == Ok((TclParse {
+///     comment: Some(""), command: Some("a b $c [d]"), span: s(0, 10),
 ///     tokens: vec![
 ///         TclToken {
-///             ttype: SimpleWord, val: "a",
-///             tokens: vec![TclToken { ttype: Text, val: "a", tokens: vec![] }]
+///             ttype: SimpleWord, val: "a", span: s(0, 1),
+///             tokens: vec![TclToken { ttype: Text, val: "a", span: s(0, 1), tokens: vec![] }]
 ///         },
 ///         TclToken {
-///             ttype: SimpleWord, val: "b",
-///             tokens: vec![TclToken { ttype: Text, val: "b", tokens: vec![] }]
+///             ttype: SimpleWord, val: "b", span: s(2, 3),
+///             tokens: vec![TclToken { ttype: Text, val: "b", span: s(2, 3), tokens: vec![] }]
 ///         },
 ///         TclToken {
-///             ttype: Word, val: "$c",
+///             ttype: Word, val: "$c", span: s(4, 6),
 ///             tokens: vec![
 ///                 TclToken {
-///                     ttype: Variable, val: "$c",
-///                     tokens: vec![TclToken { ttype: Text, val: "c", tokens: vec![] }]
+///                     ttype: Variable, val: "$c", span: s(4, 6),
+///                     tokens: vec![TclToken { ttype: Text, val: "c", span: s(5, 6), tokens: vec![] }]
 ///                 }
 ///             ]
 ///         },
 ///         TclToken {
-///             ttype: Word, val: "[d]",
-///             tokens: vec![TclToken { ttype: Command, val: "[d]", tokens: vec![] }]
+///             ttype: Word, val: "[d]", span: s(7, 10),
+///             tokens: vec![TclToken { ttype: Command, val: "[d]", span: s(7, 10), tokens: vec![] }]
 ///         }
 ///     ]
-/// }, ""));
-/// assert!(parse_command(" a\n") == (TclParse {
-///     comment: Some(""), command: Some("a\n"),
+/// }, "")));
+/// assert!(parse_command(" a\n") == Ok((TclParse {
+///     comment: Some(""), command: Some("a\n"), span: Span { start: p(1, 1, 2), end: p(3, 2, 1) },
 ///     tokens: vec![
 ///         TclToken {
-///             ttype: SimpleWord, val: "a",
-///             tokens: vec![TclToken { ttype: Text, val: "a", tokens: vec![] }]
+///             ttype: SimpleWord, val: "a", span: s(1, 2),
+///             tokens: vec![TclToken { ttype: Text, val: "a", span: s(1, 2), tokens: vec![] }]
 ///         }
 ///     ]
-/// }, ""));
-/// assert!(parse_command("a; b") == (TclParse {
-///     comment: Some(""), command: Some("a;"),
+/// }, "")));
+/// assert!(parse_command("a; b") == Ok((TclParse {
+///     comment: Some(""), command: Some("a;"), span: s(0, 2),
 ///     tokens: vec![
 ///         TclToken {
-///             ttype: SimpleWord, val: "a",
-///             tokens: vec![TclToken { ttype: Text, val: "a", tokens: vec![] }]
+///             ttype: SimpleWord, val: "a", span: s(0, 1),
+///             tokens: vec![TclToken { ttype: Text, val: "a", span: s(0, 1), tokens: vec![] }]
 ///         }
 ///     ]
-/// }, " b"));
-/// assert!(parse_command("#comment\n\n\na\n") == (TclParse {
-///     comment: Some("#comment\n"), command: Some("a\n"),
+/// }, " b")));
+/// assert!(parse_command("#comment\n\n\na\n") == Ok((TclParse {
+///     comment: Some("#comment\n"), command: Some("a\n"), span: Span { start: p(11, 4, 1), end: p(13, 5, 1) },
 ///     tokens: vec![
 ///         TclToken {
-///             ttype: SimpleWord, val: "a",
-///             tokens: vec![TclToken { ttype: Text, val: "a", tokens: vec![] }]
+///             ttype: SimpleWord, val: "a", span: Span { start: p(11, 4, 1), end: p(12, 4, 2) },
+///             tokens: vec![TclToken { ttype: Text, val: "a", span: Span { start: p(11, 4, 1), end: p(12, 4, 2) }, tokens: vec![] }]
 ///         }
 ///     ]
-/// }, ""));
+/// }, "")));
 /// ```
-pub fn parse_command<'a>(string: &'a str) -> (TclParse<'a>, &'a str) {
-    return parse(string, true, false);
+///
+/// Literal words being expanded with `{*}` are split into their elements
+/// immediately, as Tcl itself does.
+///
+/// ```
+/// use tclscan::rstcl::{TclParse,TclToken};
+/// use tclscan::rstcl::TokenType::{SimpleWord,Text};
+/// use tclscan::rstcl::parse_command;
+/// use tclscan::rstcl::{Position,Span};
+/// fn s(start: usize, end: usize) -> Span {
+///     let p = |offset| Position { offset: offset, line: 1, col: offset + 1 };
+///     return Span { start: p(start), end: p(end) };
+/// }
+/// assert!(parse_command("a {*}{b \"c\"}") == Ok((TclParse {
+///     comment: Some(""), command: Some("a {*}{b \"c\"}"), span: s(0, 12),
+///     tokens: vec![
+///         TclToken {
+///             ttype: SimpleWord, val: "a", span: s(0, 1),
+///             tokens: vec![TclToken { ttype: Text, val: "a", span: s(0, 1), tokens: vec![] }]
+///         },
+///         TclToken {
+///             ttype: SimpleWord, val: "b", span: s(6, 7),
+///             tokens: vec![TclToken { ttype: Text, val: "b", span: s(6, 7), tokens: vec![] }]
+///         },
+///         TclToken {
+///             ttype: SimpleWord, val: "\"c\"", span: s(8, 11),
+///             tokens: vec![TclToken { ttype: Text, val: "c", span: s(9, 10), tokens: vec![] }]
+///         }
+///     ]
+/// }, "")));
+/// ```
+pub fn parse_command<'a>(string: &'a str) -> Result<(TclParse<'a>, &'a str), ParseError> {
+    return parse_command_at(string, Position::start());
+}
+/// As `parse_command`, for a string starting at `pos` in the original source
+/// (e.g. the contents of a braced word being re-parsed as a script).
+///
+/// ```
+/// use tclscan::rstcl::{Position,Span};
+/// use tclscan::rstcl::parse_command_at;
+/// let base = Position { offset: 10, line: 3, col: 5 };
+/// let (parse, _) = parse_command_at("a\n b", base).unwrap();
+/// assert!(parse.span == Span { start: base, end: Position { offset: 12, line: 4, col: 1 } });
+/// let (parse, _) = parse_command_at(" b", parse.span.end).unwrap();
+/// assert!(parse.tokens[0].span == Span {
+///     start: Position { offset: 13, line: 4, col: 2 },
+///     end: Position { offset: 14, line: 4, col: 3 },
+/// });
+/// ```
+pub fn parse_command_at<'a>(string: &'a str, pos: Position) -> Result<(TclParse<'a>, &'a str), ParseError> {
+    return parse(string, pos, true, false);
 }
 /// Takes: a string, which should be a tcl script
-/// Returns: a list of parse structures representing the entire script
+/// Returns: a list of parse structures representing the entire script, or
+/// the first parse error
 ///
 /// ```
 /// use tclscan::rstcl::TclParse;
 /// use tclscan::rstcl::parse_script;
-/// assert!(parse_script(";;;   ;    ;") == vec![
-///     TclParse { comment: Some(""), command: Some(";"), tokens: vec![] },
-///     TclParse { comment: Some(""), command: Some(";"), tokens: vec![] },
-///     TclParse { comment: Some(""), command: Some(";"), tokens: vec![] },
-///     TclParse { comment: Some(""), command: Some(";"), tokens: vec![] },
-///     TclParse { comment: Some(""), command: Some(";"), tokens: vec![] }
-/// ]);
+/// use tclscan::rstcl::{Position,Span};
+/// fn s(start: usize, end: usize) -> Span {
+///     let p = |offset| Position { offset: offset, line: 1, col: offset + 1 };
+///     return Span { start: p(start), end: p(end) };
+/// }
+/// assert!(parse_script(";;;   ;    ;") == Ok(vec![
+///     TclParse { comment: Some(""), command: Some(";"), tokens: vec![], span: s(0, 1) },
+///     TclParse { comment: Some(""), command: Some(";"), tokens: vec![], span: s(1, 2) },
+///     TclParse { comment: Some(""), command: Some(";"), tokens: vec![], span: s(2, 3) },
+///     TclParse { comment: Some(""), command: Some(";"), tokens: vec![], span: s(6, 7) },
+///     TclParse { comment: Some(""), command: Some(";"), tokens: vec![], span: s(11, 12) }
+/// ]));
 /// ```
-pub fn parse_script<'a>(string: &'a str) -> Vec<TclParse<'a>> {
-    let mut script = string;
-    let mut commands = vec![];
-    while script.len() > 0 {
-        let (parse, remaining) = parse_command(script);
-        // Make sure commandless parse only happens at the end or at a semicolon
-        assert!(parse.tokens.len() > 0 || remaining.len() == 0 || parse.command == Some(";"),
+pub fn parse_script<'a>(string: &'a str) -> Result<Vec<TclParse<'a>>, ParseError> {
+    return parse_script_at(string, Position::start());
+}
+/// As `parse_script`, for a string starting at `pos` in the original source.
+pub fn parse_script_at<'a>(string: &'a str, pos: Position) -> Result<Vec<TclParse<'a>>, ParseError> {
+    return iter_script_at(string, pos).collect();
+}
+/// As `parse_script`, but lazily parses one command at a time. Iteration
+/// stops after the first parse error.
+///
+/// ```
+/// use tclscan::rstcl::iter_script;
+/// let mut parses = iter_script("a\nb {\nc");
+/// assert!(parses.next().unwrap().unwrap().command == Some("a\n"));
+/// assert!(parses.next().unwrap().unwrap_err().result == "missing close-brace");
+/// assert!(parses.next().is_none());
+/// ```
+pub fn iter_script<'a>(string: &'a str) -> ScriptIter<'a> {
+    return iter_script_at(string, Position::start());
+}
+/// As `iter_script`, for a string starting at `pos` in the original source.
+pub fn iter_script_at<'a>(string: &'a str, pos: Position) -> ScriptIter<'a> {
+    return ScriptIter { commands: CommandIter { script: string, pos: pos, recover: false } };
+}
+/// As `iter_script_at`, but when a command can't be parsed the error is
+/// yielded along with the text skipped over, and parsing resumes after the
+/// next newline.
+///
+/// ```
+/// use tclscan::rstcl::{Position,ParseError};
+/// use tclscan::rstcl::parse_script_recover_at;
+/// let parses: Vec<_> = parse_script_recover_at("a {b\nc", Position::start()).collect();
+/// assert!(parses.len() == 2);
+/// assert!(parses[0] == Err((ParseError {
+///     message: "couldn't parse command",
+///     position: Position { offset: 2, line: 1, col: 3 },
+///     result: "missing close-brace".to_owned(),
+/// }, "a {b\n")));
+/// let parse = parses[1].as_ref().unwrap();
+/// assert!(parse.command == Some("c") && parse.span.start.line == 2);
+/// // Expanding an empty list gives a command with no words
+/// let parses: Vec<_> = parse_script_recover_at("{*}{}\n{*}\"\"\nlist a", Position::start()).collect();
+/// assert!(parses.len() == 3 && parses.iter().all(|parse| parse.is_ok()));
+/// assert!(parses[0].as_ref().unwrap().tokens.len() == 0);
+/// assert!(parses[2].as_ref().unwrap().command == Some("list a"));
+/// ```
+pub fn parse_script_recover_at<'a>(string: &'a str, pos: Position) -> CommandIter<'a> {
+    return CommandIter { script: string, pos: pos, recover: true };
+}
+/// Lazily parses the commands of a script, see `iter_script`
+pub struct ScriptIter<'a> {
+    commands: CommandIter<'a>,
+}
+impl<'a> Iterator for ScriptIter<'a> {
+    type Item = Result<TclParse<'a>, ParseError>;
+    fn next(&mut self) -> Option<Result<TclParse<'a>, ParseError>> {
+        return self.commands.next().map(|parsed| parsed.map_err(|(err, _)| err));
+    }
+}
+/// Lazily parses the commands of a script, see `parse_script_recover_at`.
+/// Each call only looks at as much of the script as the next command needs,
+/// so parsing a whole script is linear in its length.
+pub struct CommandIter<'a> {
+    script: &'a str,
+    pos: Position,
+    recover: bool,
+}
+impl<'a> Iterator for CommandIter<'a> {
+    type Item = Result<TclParse<'a>, (ParseError, &'a str)>;
+    fn next(&mut self) -> Option<Result<TclParse<'a>, (ParseError, &'a str)>> {
+        let script = self.script;
+        if script.len() == 0 {
+            return None;
+        }
+        let (parse, remaining) = match parse_command_at(script, self.pos) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                if !self.recover {
+                    self.script = "";
+                    return Some(Err((err, script)));
+                }
+                // Skip to just after the first newline at or after the failure
+                let failed_at = err.offset() - self.pos.offset;
+                let resync = match script[failed_at..].find('\n') {
+                    Some(i) => failed_at + i + 1,
+                    None => script.len(),
+                };
+                let (skipped, remaining) = script.split_at(resync);
+                self.pos = parser::locate(script, self.pos, resync);
+                self.script = remaining;
+                return Some(Err((err, skipped)));
+            },
+        };
+        // Make sure commandless parse only happens at the end, at a semicolon
+        // or when the words came from expanding an empty list, e.g. `{*}{}`
+        let expands_empty = parse.command.is_some_and(|command| command.starts_with("{*}"));
+        assert!(parse.tokens.len() > 0 || remaining.len() == 0 || parse.command == Some(";") || expands_empty,
             "S:`{}` P:{:?} R:`{}`", script, parse, remaining);
-        script = remaining;
-        commands.push(parse);
+        self.script = remaining;
+        self.pos = parse.span.end;
+        return Some(Ok(parse));
     }
-    return commands;
+}
+/// An element of a Tcl list
+#[derive(Debug, PartialEq)]
+pub struct ListElement<'a> {
+    /// The element as written, without any enclosing braces or quotes
+    pub val: &'a str,
+    /// The element after backslash substitution, i.e. what Tcl sees
+    pub value: Cow<'a, str>,
+    /// Where `val` is in the original source
+    pub span: Span,
+}
+/// Takes: a string, which should be a tcl list
+/// Returns: the elements of the list, as split by Tcl_SplitList
+///
+/// ```
+/// use tclscan::rstcl::parse_list;
+/// let elems = parse_list("a {b {c}} \"d\\te\"\n  {}").unwrap();
+/// let values: Vec<_> = elems.iter().map(|elem| &*elem.value).collect();
+/// assert!(values == vec!["a", "b {c}", "d\te", ""]);
+/// assert!(elems[1].val == "b {c}" && elems[1].span.start.offset == 3);
+/// assert!(elems[3].span.start.line == 2 && elems[3].span.start.col == 4);
+/// let err = parse_list("a {b}c").unwrap_err();
+/// assert!(err.result == "list element in braces followed by \"c\" instead of space");
+/// ```
+pub fn parse_list<'a>(string: &'a str) -> Result<Vec<ListElement<'a>>, ParseError> {
+    return parse_list_at(string, Position::start());
+}
+/// As `parse_list`, for a string starting at `pos` in the original source
+/// (e.g. the contents of a braced word).
+pub fn parse_list_at<'a>(string: &'a str, pos: Position) -> Result<Vec<ListElement<'a>>, ParseError> {
+    let elems = parser::split_list(string, pos).map_err(|failure| ParseError {
+        message: "couldn't parse list",
+        position: parser::locate(string, pos, failure.offset),
+        result: failure.msg,
+    })?;
+    return Ok(elems.into_iter().map(|(start, end, literal, span)| {
+        let val = &string[start..end];
+        let value = if literal { Cow::Borrowed(val) } else { Cow::Owned(parser::collapse(val)) };
+        ListElement { val: val, value: value, span: span }
+    }).collect());
 }
 /// Takes: a string, which should be a tcl expr
-/// Returns: a parse structure and the remaining script.
+/// Returns: a parse structure and the remaining script, or why it couldn't be
+/// parsed.
 ///
 /// ```
 /// use tclscan::rstcl::{TclParse,TclToken};
 /// use tclscan::rstcl::TokenType::{SubExpr,Text,Variable,Command,Operator};
 /// use tclscan::rstcl::parse_expr;
-/// assert!(parse_expr("[a]+$b+cos([c]+$d)") == (TclParse {
-///     comment: None, command: None,
+/// use tclscan::rstcl::{Position,Span};
+/// fn s(start: usize, end: usize) -> Span {
+///     let p = |offset| Position { offset: offset, line: 1, col: offset + 1 };
+///     return Span { start: p(start), end: p(end) };
+/// }
+/// assert!(parse_expr("[a]+$b+cos([c]+$d)") == Ok((TclParse {
+///     comment: None, command: None, span: s(0, 18),
 ///     tokens: vec![
 ///         TclToken {
-///             ttype: SubExpr, val: "[a]+$b+cos([c]+$d)",
+///             ttype: SubExpr, val: "[a]+$b+cos([c]+$d)", span: s(0, 18),
 ///             tokens: vec![
-///                 TclToken { ttype: Operator, val: "+", tokens: vec![] },
+///                 TclToken { ttype: Operator, val: "+", span: s(6, 7), tokens: vec![] },
 ///                 TclToken {
-///                     ttype: SubExpr, val: "[a]+$b",
+///                     ttype: SubExpr, val: "[a]+$b", span: s(0, 6),
 ///                     tokens: vec![
-///                         TclToken { ttype: Operator, val: "+", tokens: vec![] },
+///                         TclToken { ttype: Operator, val: "+", span: s(3, 4), tokens: vec![] },
 ///                         TclToken {
-///                             ttype: SubExpr, val: "[a]",
+///                             ttype: SubExpr, val: "[a]", span: s(0, 3),
 ///                             tokens: vec![
-///                                 TclToken { ttype: Command, val: "[a]", tokens: vec![] }
+///                                 TclToken { ttype: Command, val: "[a]", span: s(0, 3), tokens: vec![] }
 ///                             ]
 ///                         },
 ///                         TclToken {
-///                             ttype: SubExpr, val: "$b",
+///                             ttype: SubExpr, val: "$b", span: s(4, 6),
 ///                             tokens: vec![
 ///                                 TclToken {
-///                                     ttype: Variable, val: "$b",
+///                                     ttype: Variable, val: "$b", span: s(4, 6),
 ///                                     tokens: vec![
-///                                         TclToken { ttype: Text, val: "b", tokens: vec![] }
+///                                         TclToken { ttype: Text, val: "b", span: s(5, 6), tokens: vec![] }
 ///                                     ]
 ///                                 }
 ///                             ]
@@ -209,26 +507,26 @@ pub fn parse_script<'a>(string: &'a str) -> Vec<TclParse<'a>> {
 ///                     ]
 ///                 },
 ///                 TclToken {
-///                     ttype: SubExpr, val: "cos([c]+$d)",
+///                     ttype: SubExpr, val: "cos([c]+$d)", span: s(7, 18),
 ///                     tokens: vec![
-///                         TclToken { ttype: Operator, val: "cos", tokens: vec![] },
+///                         TclToken { ttype: Operator, val: "cos", span: s(7, 10), tokens: vec![] },
 ///                         TclToken {
-///                             ttype: SubExpr, val: "[c]+$d",
+///                             ttype: SubExpr, val: "[c]+$d", span: s(11, 17),
 ///                             tokens: vec![
-///                                 TclToken { ttype: Operator, val: "+", tokens: vec![] },
+///                                 TclToken { ttype: Operator, val: "+", span: s(14, 15), tokens: vec![] },
 ///                                 TclToken {
-///                                     ttype: SubExpr, val: "[c]",
+///                                     ttype: SubExpr, val: "[c]", span: s(11, 14),
 ///                                     tokens: vec![
-///                                         TclToken { ttype: Command, val: "[c]", tokens: vec![] }
+///                                         TclToken { ttype: Command, val: "[c]", span: s(11, 14), tokens: vec![] }
 ///                                     ]
 ///                                 },
 ///                                 TclToken {
-///                                     ttype: SubExpr, val: "$d",
+///                                     ttype: SubExpr, val: "$d", span: s(15, 17),
 ///                                     tokens: vec![
 ///                                         TclToken {
-///                                             ttype: Variable, val: "$d",
+///                                             ttype: Variable, val: "$d", span: s(15, 17),
 ///                                             tokens: vec![
-///                                                 TclToken { ttype: Text, val: "d", tokens: vec![] }
+///                                                 TclToken { ttype: Text, val: "d", span: s(16, 17), tokens: vec![] }
 ///                                             ]
 ///                                         }
 ///                                     ]
@@ -240,172 +538,112 @@ pub fn parse_script<'a>(string: &'a str) -> Vec<TclParse<'a>> {
 ///             ]
 ///         }
 ///     ]
-/// }, ""));
-/// assert!(parse_expr("1") == (TclParse {
-///     comment: None, command: None,
+/// }, "")));
+/// assert!(parse_expr("1") == Ok((TclParse {
+///     comment: None, command: None, span: s(0, 1),
 ///     tokens: vec![
 ///         TclToken {
-///             ttype: SubExpr, val: "1",
-///             tokens: vec![TclToken { ttype: Text, val: "1", tokens: vec![] }]
+///             ttype: SubExpr, val: "1", span: s(0, 1),
+///             tokens: vec![TclToken { ttype: Text, val: "1", span: s(0, 1), tokens: vec![] }]
 ///         }
 ///     ]
-/// }, ""));
+/// }, "")));
+/// assert!(parse_expr("1 +").unwrap_err().result == "missing operand");
 /// ```
-pub fn parse_expr<'a>(string: &'a str) -> (TclParse<'a>, &'a str) {
-    return parse(string, false, true);
+pub fn parse_expr<'a>(string: &'a str) -> Result<(TclParse<'a>, &'a str), ParseError> {
+    return parse_expr_at(string, Position::start());
 }
-
-fn parse<'a>(string: &'a str, is_command: bool, is_expr: bool) -> (TclParse<'a>, &'a str) {
-    unsafe {
-        let mut parse: tcl::Tcl_Parse = uninitialized();
-        let parse_ptr: *mut tcl::Tcl_Parse = &mut parse;
-
-        // https://github.com/rust-lang/rust/issues/16035
-        let string_cstr = CString::new(string.as_bytes()).unwrap();
-        let string_ptr = string_cstr.as_ptr();
-        let string_start = string_ptr as usize;
-
-        let parsed = match (is_command, is_expr) {
-            // interp, start, numBytes, nested, parsePtr
-            (true, false) => tcl::Tcl_ParseCommand(tcl_interp(), string_ptr, -1, 0, parse_ptr),
-            // interp, start, numBytes, parsePtr
-            (false, true) => tcl::Tcl_ParseExpr(tcl_interp(), string_ptr, -1, parse_ptr),
-            parse_args => panic!("Don't know how to parse {:?}", parse_args),
-        };
-        if parsed != 0 {
-            println!("WARN: couldn't parse {}", string);
-            return (TclParse { comment: Some(""), command: Some(""), tokens: vec![] }, "");
-        }
-        let tokens = make_tokens(string, string_start, &parse);
-
-        let (tclparse, remaining) = match (is_command, is_expr) {
-            (true, false) => {
-                assert!(tokens.len() == parse.numWords as usize);
-                // commentStart seems to be undefined if commentSize == 0
-                let comment = Some(match parse.commentSize as usize {
-                    0 => "",
-                    l => {
-                        let offset = parse.commentStart as usize - string_start;
-                        &string[offset..offset+l]
-                    },
-                });
-                let command_len = parse.commandSize as usize;
-                let command_off = parse.commandStart as usize - string_start;
-                let command = Some(&string[command_off..command_off+command_len]);
-                let remaining = &string[command_off+command_len..];
-                (TclParse { comment: comment, command: command, tokens: tokens }, remaining)
-            },
-            (false, true) => {
-                (TclParse { comment: None, command: None, tokens: tokens }, "")
-            },
-            _ => panic!("Unreachable"),
-        };
-
-        tcl::Tcl_FreeParse(parse_ptr);
-        return (tclparse, remaining);
-    }
+/// As `parse_expr`, for a string starting at `pos` in the original source.
+pub fn parse_expr_at<'a>(string: &'a str, pos: Position) -> Result<(TclParse<'a>, &'a str), ParseError> {
+    return parse(string, pos, false, true);
 }
 
-unsafe fn make_tokens<'a>(string: &'a str, string_start: usize, tcl_parse: &tcl::Tcl_Parse) -> Vec<TclToken<'a>> {
-    let mut acc = vec![];
-    for i in (0..tcl_parse.numTokens as isize).rev() {
-        let tcl_token = *(tcl_parse.tokenPtr).offset(i);
-        assert!(tcl_token.start as usize > 0);
-        let offset = tcl_token.start as usize - string_start;
-        let token_size = tcl_token.size as usize;
-        let tokenval = &string[offset..offset+token_size];
-        make_tcltoken(&tcl_token, tokenval, &mut acc);
+/// Which substitutions `subst` does, i.e. those not turned off with
+/// `-nobackslashes`, `-nocommands` or `-novariables`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SubstFlags {
+    pub backslashes: bool,
+    pub commands: bool,
+    pub variables: bool,
+}
+impl Default for SubstFlags {
+    fn default() -> SubstFlags {
+        return SubstFlags { backslashes: true, commands: true, variables: true };
     }
-    acc.reverse();
-    return acc;
 }
 
-fn count_tokens(token: &TclToken) -> usize {
-    token.tokens.iter().map(|t| count_tokens(t)).sum::<usize>() + 1
+/// Takes: a string, as would be passed to `subst`
+/// Returns: the text, backslash, variable and command substitution tokens in
+/// it
+///
+/// ```
+/// use tclscan::rstcl::parse_subst;
+/// use tclscan::rstcl::TokenType::*;
+/// let tokens = parse_subst("a $b [c]\\n").unwrap().0.tokens;
+/// let types: Vec<_> = tokens.iter().map(|token| (token.ttype, token.val)).collect();
+/// assert!(types == vec![(Text, "a "), (Variable, "$b"), (Text, " "), (Command, "[c]"), (Bs, "\\n")]);
+/// assert!(parse_subst("[a").unwrap_err().result == "missing close-bracket");
+/// ```
+pub fn parse_subst<'a>(string: &'a str) -> Result<(TclParse<'a>, &'a str), ParseError> {
+    return parse_subst_at(string, Position::start());
+}
+/// As `parse_subst`, for a string starting at `pos` in the original source.
+pub fn parse_subst_at<'a>(string: &'a str, pos: Position) -> Result<(TclParse<'a>, &'a str), ParseError> {
+    return parse_subst_flags_at(string, pos, SubstFlags::default());
+}
+/// As `parse_subst_at`, only doing the substitutions in `flags`. The
+/// characters which would start the others are left as text.
+///
+/// ```
+/// use tclscan::rstcl::{parse_subst_flags_at,Position,SubstFlags};
+/// use tclscan::rstcl::TokenType::*;
+/// let flags = SubstFlags { commands: false, ..SubstFlags::default() };
+/// let tokens = parse_subst_flags_at("[a $b([c])", Position::start(), flags).unwrap().0.tokens;
+/// let types: Vec<_> = tokens.iter().map(|token| (token.ttype, token.val)).collect();
+/// // Array element names are always substituted
+/// assert!(types == vec![(Text, "["), (Text, "a "), (Variable, "$b([c])")]);
+/// assert!(tokens[2].tokens[1].ttype == Command);
+/// ```
+pub fn parse_subst_flags_at<'a>(string: &'a str, pos: Position, flags: SubstFlags) -> Result<(TclParse<'a>, &'a str), ParseError> {
+    let mut parser_flags = 0;
+    if flags.backslashes {
+        parser_flags |= parser::SUBST_BACKSLASHES;
+    }
+    if flags.commands {
+        parser_flags |= parser::SUBST_COMMANDS;
+    }
+    if flags.variables {
+        parser_flags |= parser::SUBST_VARIABLES;
+    }
+    return parser::parse_subst(string, pos, parser_flags).map(|(tokens, span)| {
+        (TclParse { comment: None, command: None, tokens: tokens, span: span }, "")
+    }).map_err(|failure| ParseError {
+        message: "couldn't parse substitutions",
+        position: parser::locate(string, pos, failure.offset),
+        result: failure.msg,
+    });
 }
 
-fn make_tcltoken<'a>(tcl_token: &tcl::Tcl_Token, tokenval: &'a str, acc: &mut Vec<TclToken<'a>>) {
-    let token_type: TokenType = TokenType::from_usize(tcl_token.type_ as usize).unwrap();
-    let num_subtokens = tcl_token.numComponents as usize;
-
-    let subtokens = match token_type {
-        Word | ExpandWord => {
-            let mut subtokens = vec![];
-            let mut count = 0;
-            while count < num_subtokens {
-                assert!(acc.len() > 0);
-                let tok = acc.pop().unwrap();
-                count += count_tokens(&tok);
-                subtokens.push(tok);
-            }
-            assert!(count == num_subtokens);
-            subtokens
-        },
-        SimpleWord => {
-            assert!(acc.len() > 0);
-            assert!(num_subtokens == 1);
-            let tok = acc.pop().unwrap();
-            assert!(tok.ttype == Text);
-            vec![tok]
-        },
-        Text | Bs => {
-            assert!(num_subtokens == 0);
-            vec![]
-        },
-        Command => {
-            assert!(tokenval.chars().nth(0) == Some('['));
-            assert!(num_subtokens == 0);
-            vec![]
-        },
-        Variable => {
-            assert!(acc.len() > 0);
-            let tok = acc.pop().unwrap();
-            assert!(tok.ttype == Text);
-            let mut subtokens = vec![tok];
-            let mut count = 1;
-            while count < num_subtokens {
-                assert!(acc.len() > 0);
-                let tok = acc.pop().unwrap();
-                count += match tok.ttype {
-                    Text | Bs | Command | Variable => count_tokens(&tok),
-                    _ => panic!("Invalid token type {:?}", tok.ttype),
-                };
-                subtokens.push(tok);
-            }
-            assert!(count == num_subtokens);
-            subtokens
-        },
-        SubExpr => {
-            assert!(acc.len() > 0);
-            let start_ttype = acc[acc.len()-1].ttype;
-            let mut subtokens = vec![];
-            let mut count = 0;
-            if start_ttype == Operator {
-                subtokens.push(acc.pop().unwrap());
-                count += 1;
-            }
-            while count < num_subtokens {
-                assert!(acc.len() > 0);
-                let tok = acc.pop().unwrap();
-                if start_ttype == Operator {
-                    assert!(tok.ttype == SubExpr);
-                }
-                match tok.ttype {
-                    Word | Text | Bs | Command | Variable | SubExpr => {
-                        count += count_tokens(&tok)
-                    },
-                    _ => panic!("Invalid token {:?}", tok.ttype),
-                }
-                subtokens.push(tok);
-            }
-            assert!(count == num_subtokens);
-            subtokens
-        },
-        Operator => {
-            assert!(acc.len() > 0);
-            assert!(num_subtokens == 0);
-            vec![]
-        },
+fn parse<'a>(string: &'a str, pos: Position, is_command: bool, is_expr: bool) -> Result<(TclParse<'a>, &'a str), ParseError> {
+    let (parsed, message) = match (is_command, is_expr) {
+        (true, false) => (parser::parse_command(string, pos).map(|parse| {
+            // No comment is represented as an empty comment
+            let comment = Some(match parse.comment {
+                Some((start, end)) => &string[start..end],
+                None => "",
+            });
+            let command = Some(&string[parse.command_start..parse.command_end]);
+            let remaining = &string[parse.command_end..];
+            (TclParse { comment: comment, command: command, tokens: parse.tokens, span: parse.span }, remaining)
+        }), "couldn't parse command"),
+        (false, true) => (parser::parse_expr(string, pos).map(|(tokens, span)| {
+            (TclParse { comment: None, command: None, tokens: tokens, span: span }, "")
+        }), "couldn't parse expression"),
+        parse_args => panic!("Don't know how to parse {:?}", parse_args),
     };
-    acc.push(TclToken { val: tokenval, tokens: subtokens, ttype: token_type })
+    return parsed.map_err(|failure| ParseError {
+        message: message,
+        position: parser::locate(string, pos, failure.offset),
+        result: failure.msg,
+    });
 }
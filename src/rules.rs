@@ -0,0 +1,167 @@
+//! The checks made by `scan_script`. Each has a stable id so it can be
+//! referred to from the command line, configuration and suppressions.
+
+use serde::Serialize;
+use Severity;
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Rule {
+    /// Stable identifier, e.g. `dangerous-unquoted-block`
+    pub id: &'static str,
+    pub severity: Severity,
+    /// Short description, as shown alongside each finding
+    pub message: &'static str,
+    /// What the rule finds and why it matters
+    pub description: &'static str,
+    /// The closest Common Weakness Enumeration entry, if any
+    pub cwe: Option<u32>,
+}
+
+pub static UNQUOTED_BLOCK: Rule = Rule {
+    id: "unquoted-block",
+    severity: Severity::Warn,
+    message: "Unquoted block",
+    description: "A script argument (e.g. of eval or the body of if) isn't braced. Its value looks \
+                  safe, but it's substituted before being evaluated, so braces make the intent clear.",
+    cwe: Some(95),
+};
+
+pub static DANGEROUS_UNQUOTED_BLOCK: Rule = Rule {
+    id: "dangerous-unquoted-block",
+    severity: Severity::Danger,
+    message: "Dangerous unquoted block",
+    description: "A script argument (e.g. of eval or the body of if) isn't braced and contains a \
+                  variable or command substitution, so whatever it's substituted with is evaluated \
+                  as a script.",
+    cwe: Some(95),
+};
+
+pub static UNQUOTED_EXPR: Rule = Rule {
+    id: "unquoted-expr",
+    severity: Severity::Warn,
+    message: "Unquoted expr",
+    description: "An expression argument (e.g. of expr or the condition of if) isn't braced. Its \
+                  value looks safe, but it's substituted twice, so braces make the intent clear and \
+                  let the expression be compiled.",
+    cwe: Some(95),
+};
+
+pub static DANGEROUS_UNQUOTED_EXPR: Rule = Rule {
+    id: "dangerous-unquoted-expr",
+    severity: Severity::Danger,
+    message: "Dangerous unquoted expr",
+    description: "An expression argument (e.g. of expr or the condition of if) isn't braced and \
+                  contains a variable or command substitution, so whatever it's substituted with is \
+                  substituted again, which can run arbitrary commands.",
+    cwe: Some(95),
+};
+
+pub static DANGEROUS_CALLBACK: Rule = Rule {
+    id: "dangerous-callback",
+    severity: Severity::Danger,
+    message: "Dangerous callback script",
+    description: "A script run later by the event loop (e.g. by after or fileevent) is built by \
+                  substituting values into a string, so they're evaluated as part of the script \
+                  when it runs. Build it with list instead, e.g. `[list handle $chan]`.",
+    cwe: Some(95),
+};
+
+pub static DANGEROUS_COMMAND_PREFIX: Rule = Rule {
+    id: "dangerous-command-prefix",
+    severity: Severity::Danger,
+    message: "Dangerous command prefix",
+    description: "A command prefix (e.g. of lsort -command or trace add) is built by substituting \
+                  values into a string, which is then split into a command and its arguments, so \
+                  the values can add words or run other commands. Build it with list instead.",
+    cwe: Some(88),
+};
+
+pub static NON_LITERAL_COMMAND: Rule = Rule {
+    id: "non-literal-command",
+    severity: Severity::Warn,
+    message: "Non-literal command, cannot scan",
+    description: "The name of the command to run comes from a substitution, so it can't be known \
+                  what's run or how its arguments are used.",
+    cwe: Some(470),
+};
+
+pub static BADLY_FORMED_COMMAND: Rule = Rule {
+    id: "badly-formed-command",
+    severity: Severity::Warn,
+    message: "badly formed command",
+    description: "A command which takes scripts or expressions as arguments has the wrong number \
+                  of arguments, so they can't be scanned.",
+    cwe: Some(628),
+};
+
+pub static VARIABLE_IN_LITERAL: Rule = Rule {
+    id: "variable-in-literal",
+    severity: Severity::Danger,
+    message: "Expected literal, found $",
+    description: "An argument which names something (e.g. a proc or a variable to set) contains \
+                  a variable substitution, so what it names is controlled by the variable.",
+    cwe: Some(914),
+};
+
+pub static COMMAND_IN_LITERAL: Rule = Rule {
+    id: "command-in-literal",
+    severity: Severity::Danger,
+    message: "Expected literal, found [",
+    description: "An argument which names something (e.g. a proc or a variable to set) contains \
+                  a command substitution, so what it names is controlled by the command.",
+    cwe: Some(914),
+};
+
+pub static COMMENT_IN_SWITCH: Rule = Rule {
+    id: "comment-in-switch",
+    severity: Severity::Warn,
+    message: "Comment in switch patterns",
+    description: "A word starting with `#` among the patterns and bodies of a switch is taken as a \
+                  pattern rather than a comment, which misaligns the patterns and bodies after it.",
+    cwe: None,
+};
+
+pub static PARSE_FAILURE: Rule = Rule {
+    id: "parse-failure",
+    severity: Severity::Error,
+    message: "couldn't parse",
+    description: "Some code isn't valid Tcl, so it wasn't scanned.",
+    cwe: None,
+};
+
+pub static UNUSED_SUPPRESSION: Rule = Rule {
+    id: "unused-suppression",
+    severity: Severity::Warn,
+    message: "Suppression doesn't match any finding",
+    description: "A `# tclscan: ignore` comment doesn't suppress anything, so it's stale or names \
+                  the wrong rule. Only reported with --report-unused-suppressions.",
+    cwe: None,
+};
+
+/// Every rule, in the order they're listed by `tclscan rules`
+pub static RULES: [&'static Rule; 13] = [
+    &DANGEROUS_UNQUOTED_BLOCK,
+    &DANGEROUS_UNQUOTED_EXPR,
+    &DANGEROUS_CALLBACK,
+    &DANGEROUS_COMMAND_PREFIX,
+    &VARIABLE_IN_LITERAL,
+    &COMMAND_IN_LITERAL,
+    &UNQUOTED_BLOCK,
+    &UNQUOTED_EXPR,
+    &NON_LITERAL_COMMAND,
+    &BADLY_FORMED_COMMAND,
+    &COMMENT_IN_SWITCH,
+    &PARSE_FAILURE,
+    &UNUSED_SUPPRESSION,
+];
+
+/// Looks up a rule by its id
+///
+/// ```
+/// use tclscan::rules::{self, DANGEROUS_UNQUOTED_EXPR};
+/// assert!(rules::find("dangerous-unquoted-expr") == Some(&DANGEROUS_UNQUOTED_EXPR));
+/// assert!(rules::find("Dangerous unquoted expr") == None);
+/// ```
+pub fn find(id: &str) -> Option<&'static Rule> {
+    return RULES.iter().find(|rule| rule.id == id).copied();
+}
@@ -0,0 +1,452 @@
+//! Signatures describing how commands interpret their arguments, so that
+//! arguments which are scripts or expressions can be checked.
+//!
+//! A signature is a sequence of:
+//!
+//! - an argument kind: `block` (a script), `expr`, `literal` (a name, which
+//!   shouldn't be substituted), `normal` (any value) or `pattern-bodies` (a
+//!   list of patterns and scripts, as taken by `switch`), `subst` (a string
+//!   which is substituted), `callback` (a script run later, which can be
+//!   built with `list`) or `command-prefix` (a list of a command and some of
+//!   its arguments, which more are added to when it's run)
+//! - `level`, a stack level like `1` or `#0` as taken by `uplevel`
+//! - a keyword in single quotes, e.g. `'else'`, matching an argument with
+//!   exactly that value
+//! - a group of items in parentheses, or of alternatives separated by `|`
+//! - a run of options, e.g. `-nocase -index=normal`, each a flag or (with
+//!   `=kind`) an option taking a value. Any number of them are accepted in any
+//!   order, optionally ended by `--`.
+//!
+//! Kinds, keywords and groups can be followed by `?` (optional), `*` (any
+//! number) or `+` (at least one). For example, `if` is
+//! `expr 'then'? block ('elseif' expr 'then'? block)* ('else'? block)?`.
+//!
+//! A signature can be given for a subcommand, e.g. `namespace eval`, in which
+//! case it describes the arguments after the subcommand.
+//!
+//! Signatures for builtin commands are in `signatures.toml`, and more can be
+//! loaded from files in the same format or the configuration file.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use serde::Deserialize;
+use toml;
+use rstcl::{TclToken, TokenType};
+use Code;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Term {
+    Kind(Code),
+    Keyword(String),
+    Level,
+    /// Alternative sequences of items, the first preferred
+    Group(Vec<Vec<Item>>),
+    /// Option names and the kinds of their values, if they take one
+    Options(Vec<(String, Option<Code>)>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Item {
+    term: Term,
+    min: usize,
+    max: Option<usize>,
+}
+
+/// How a command interprets its arguments
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    items: Vec<Item>,
+}
+
+// Possible places matching could have got to, with the kinds of the
+// arguments matched on the way, most preferred first. Only the first way of
+// getting to each place is kept.
+type Matches = Vec<(usize, Vec<Code>)>;
+
+fn push_match(matches: &mut Matches, end: usize, codes: Vec<Code>) {
+    if !matches.iter().any(|&(e, _)| e == end) {
+        matches.push((end, codes));
+    }
+}
+
+impl Signature {
+    /// ```
+    /// use tclscan::Code::*;
+    /// use tclscan::rstcl::parse_command;
+    /// use tclscan::signatures::Signature;
+    /// fn m(signature: &str, command: &str) -> Option<Vec<tclscan::Code>> {
+    ///     let tokens = parse_command(command).unwrap().0.tokens;
+    ///     return Signature::parse(signature).unwrap().arg_codes(&tokens[1..]);
+    /// }
+    /// let sig = "expr 'then'? block ('elseif' expr 'then'? block)* ('else'? block)?";
+    /// assert!(m(sig, "if a b") == Some(vec![Expr, Block]));
+    /// assert!(m(sig, "if a then b c") == Some(vec![Expr, Literal, Block, Block]));
+    /// assert!(m(sig, "if a b elseif c d else e") == Some(vec![Expr, Block, Literal, Expr, Block, Literal, Block]));
+    /// assert!(m(sig, "if a") == None);
+    /// let sig = "-nocase -index=normal normal block";
+    /// assert!(m(sig, "c -index 1 -nocase -- -x y") == Some(vec![Literal, Normal, Literal, Literal, Normal, Block]));
+    /// assert!(m(sig, "c x y") == Some(vec![Normal, Block]));
+    /// assert!(m("(literal normal)+ block", "foreach a b c d e") == Some(vec![Literal, Normal, Literal, Normal, Block]));
+    /// assert!(m("normal ((normal ('-' | block))+ | pattern-bodies)", "switch $x {a b}") == Some(vec![Normal, PatternBodies]));
+    /// assert!(m("normal ((normal ('-' | block))+ | pattern-bodies)", "switch $x a - b c") == Some(vec![Normal, Normal, Literal, Normal, Block]));
+    /// assert!(m("-nocase -index=normal normal", "lsort {*}$opts $l") == Some(vec![Normal, Normal]));
+    /// assert!(m("-nocase normal ((normal block)+ | pattern-bodies)", "switch {*}$o $x {a b}") == Some(vec![Normal, Normal, PatternBodies]));
+    /// assert!(m("-nocase normal ((normal block)+ | pattern-bodies)", "switch -- {a b}") == None);
+    /// assert!(m(sig, "if a b else") == None);
+    /// assert!(m("expr block", "while {*}$a") == Some(vec![Expr]));
+    /// assert!(m("level? block+", "uplevel #0 a b") == Some(vec![Normal, Block, Block]));
+    /// assert!(m("level? block+", "uplevel $l a") == Some(vec![Block, Block]));
+    /// assert!(Signature::parse("block (expr").is_err());
+    /// assert!(Signature::parse("block | expr").is_err());
+    /// ```
+    pub fn parse(string: &str) -> Result<Signature, String> {
+        let mut spaced = String::new();
+        for c in string.chars() {
+            match c {
+                '(' | ')' | '|' => spaced.push_str(&format!(" {} ", c)),
+                c => spaced.push(c),
+            }
+        }
+        let words: Vec<&str> = spaced.split_whitespace().collect();
+        let (items, used) = parse_items(&words, 0)?;
+        if used != words.len() {
+            return Err(format!("unexpected `{}` in signature `{}`", words[used], string));
+        }
+        return Ok(Signature { items: items });
+    }
+
+    /// The kind of each of `args`, or None if they don't fit the signature.
+    ///
+    /// An expanded argument (`{*}$args`) could be any number of words, so
+    /// each likely number is tried, and its kind is that of its first word.
+    /// One among the options the signature starts with is most likely only
+    /// options, so is tried as no words first.
+    ///
+    /// A word which is one of the signature's keywords is never a block,
+    /// e.g. the `else` of `if {1} {} else`, as Tcl would take it as the
+    /// keyword.
+    pub fn arg_codes(&self, args: &[TclToken]) -> Option<Vec<Code>> {
+        let values: Vec<Option<Cow<str>>> = args.iter().map(|arg| arg.literal()).collect();
+        let expanded: Vec<usize> = args.iter().enumerate()
+            .filter(|&(_, arg)| arg.ttype == TokenType::ExpandWord)
+            .map(|(i, _)| i)
+            .collect();
+        let starts_with_options = match self.items.first() {
+            Some(&Item { term: Term::Options(_), .. }) => true,
+            _ => false,
+        };
+        let counts: Vec<&[usize]> = expanded.iter().map(|&e| -> &[usize] {
+            let is_option = starts_with_options &&
+                values[..e].iter().all(|value| value.as_deref().is_some_and(|value| value.starts_with("-")));
+            match (expanded.len() <= MAX_EXPANSIONS, is_option) {
+                (true, true) => &OPTION_EXPANSION_COUNTS,
+                (true, false) => &EXPANSION_COUNTS,
+                (false, _) => &EXPANSION_COUNTS[..1],
+            }
+        }).collect();
+        let num_counts = counts.first().map_or(1, |counts| counts.len());
+        let keywords = keywords(&self.items);
+        // Try every combination of counts, the first expansion varying slowest
+        let combinations = num_counts.pow(expanded.len() as u32);
+        for combination in 0..combinations {
+            let mut words = vec![];
+            let mut origins = vec![];
+            for (i, value) in values.iter().enumerate() {
+                let count = match expanded.iter().position(|&e| e == i) {
+                    Some(n) => counts[n][combination / num_counts.pow((expanded.len() - n - 1) as u32) % num_counts],
+                    None => 1,
+                };
+                for _ in 0..count {
+                    words.push(value.as_deref());
+                    origins.push(i);
+                }
+            }
+            let matched = match_items(&self.items, &words, 0).into_iter()
+                .find(|&(end, ref codes)| end == words.len() && !codes.iter().zip(words.iter()).any(|(code, word)| {
+                    *code == Code::Block && word.is_some_and(|word| keywords.contains(&word))
+                }));
+            if let Some((_, codes)) = matched {
+                return Some((0..args.len()).map(|i| {
+                    origins.iter().position(|&origin| origin == i).map_or(Code::Normal, |word| codes[word].clone())
+                }).collect());
+            }
+        }
+        return None;
+    }
+}
+
+// How many words an expanded argument is tried as, most likely first
+const EXPANSION_COUNTS: [usize; 4] = [1, 0, 2, 3];
+// The same, for an expanded argument where options are expected
+const OPTION_EXPANSION_COUNTS: [usize; 4] = [0, 1, 2, 3];
+// Beyond this many expanded arguments, each is only tried as one word
+const MAX_EXPANSIONS: usize = 3;
+
+fn parse_items(words: &[&str], mut i: usize) -> Result<(Vec<Item>, usize), String> {
+    let mut items = vec![];
+    while i < words.len() {
+        let word = words[i];
+        if word == ")" || word == "|" {
+            break;
+        }
+        i += 1;
+        if word.starts_with("-") {
+            let (name, kind) = match word.find('=') {
+                Some(eq) => (&word[..eq], Some(parse_kind(&word[eq+1..])?)),
+                None => (word, None),
+            };
+            // Consecutive options go together
+            if let Some(&mut Item { term: Term::Options(ref mut options), .. }) = items.last_mut() {
+                options.push((name.to_owned(), kind));
+                continue;
+            }
+            items.push(Item { term: Term::Options(vec![(name.to_owned(), kind)]), min: 0, max: Some(1) });
+            continue;
+        }
+        let (word, min, max) = match word.chars().last() {
+            Some('?') => (&word[..word.len()-1], 0, Some(1)),
+            Some('*') => (&word[..word.len()-1], 0, None),
+            Some('+') => (&word[..word.len()-1], 1, None),
+            _ => (word, 1, Some(1)),
+        };
+        let term = if word == "(" {
+            let mut group = vec![];
+            loop {
+                let (alternative, end) = parse_items(words, i)?;
+                group.push(alternative);
+                if end == words.len() {
+                    return Err("missing `)` in signature".to_owned());
+                }
+                i = end + 1;
+                if words[end] == ")" {
+                    break;
+                }
+            }
+            // The repetition follows the closing paren
+            let (min, max) = match words.get(i) {
+                Some(&"?") => (0, Some(1)),
+                Some(&"*") => (0, None),
+                Some(&"+") => (1, None),
+                _ => (1, Some(1)),
+            };
+            if min != 1 || max != Some(1) {
+                i += 1;
+            }
+            items.push(Item { term: Term::Group(group), min: min, max: max });
+            continue;
+        } else if word.len() >= 2 && word.starts_with("'") && word.ends_with("'") {
+            Term::Keyword(word[1..word.len()-1].to_owned())
+        } else if word == "level" {
+            Term::Level
+        } else {
+            Term::Kind(parse_kind(word)?)
+        };
+        items.push(Item { term: term, min: min, max: max });
+    }
+    return Ok((items, i));
+}
+
+// The keywords anywhere in `items`
+fn keywords(items: &[Item]) -> Vec<&str> {
+    let mut keywords = vec![];
+    for item in items.iter() {
+        match item.term {
+            Term::Keyword(ref keyword) => keywords.push(&**keyword),
+            Term::Group(ref group) => for alternative in group.iter() {
+                keywords.extend(self::keywords(alternative).into_iter());
+            },
+            _ => (),
+        }
+    }
+    return keywords;
+}
+
+fn parse_kind(word: &str) -> Result<Code, String> {
+    return match word {
+        "block" => Ok(Code::Block),
+        "expr" => Ok(Code::Expr),
+        "literal" => Ok(Code::Literal),
+        "normal" => Ok(Code::Normal),
+        "pattern-bodies" => Ok(Code::PatternBodies),
+        "subst" => Ok(Code::Subst),
+        "callback" => Ok(Code::Callback),
+        "command-prefix" => Ok(Code::CommandPrefix),
+        _ => Err(format!("unknown argument kind `{}` in signature", word)),
+    };
+}
+
+fn match_items(items: &[Item], values: &[Option<&str>], pos: usize) -> Matches {
+    let mut states = vec![(pos, vec![])];
+    for item in items.iter() {
+        let mut next = vec![];
+        for (p, codes) in states.into_iter() {
+            for (end, more) in match_item(item, values, p) {
+                let mut codes = codes.clone();
+                codes.extend(more.into_iter());
+                push_match(&mut next, end, codes);
+            }
+        }
+        states = next;
+    }
+    return states;
+}
+
+fn match_group(group: &[Vec<Item>], values: &[Option<&str>], pos: usize) -> Matches {
+    let mut matches = vec![];
+    for items in group.iter() {
+        for (end, codes) in match_items(items, values, pos) {
+            push_match(&mut matches, end, codes);
+        }
+    }
+    return matches;
+}
+
+// Matches a repeated item, preferring as many repetitions as possible
+fn match_item(item: &Item, values: &[Option<&str>], pos: usize) -> Matches {
+    // A run of options takes all the options there are, so e.g. the `--` of
+    // `switch -- {a b}` can't be the string
+    if let Term::Options(_) = item.term {
+        return match_term(&item.term, values, pos);
+    }
+    let mut levels = vec![vec![(pos, vec![])]];
+    loop {
+        let count = levels.len() - 1;
+        if item.max.is_some_and(|max| count >= max) {
+            break;
+        }
+        let mut next = vec![];
+        for &(p, ref codes) in levels[count].iter() {
+            for (end, more) in match_term(&item.term, values, p) {
+                // Each repetition has to match something, or this would
+                // never end
+                if end > p {
+                    let mut codes = codes.clone();
+                    codes.extend(more.into_iter());
+                    push_match(&mut next, end, codes);
+                }
+            }
+        }
+        if next.len() == 0 {
+            break;
+        }
+        levels.push(next);
+    }
+    let mut matches = vec![];
+    for (count, level) in levels.into_iter().enumerate().rev() {
+        if count >= item.min {
+            for (end, codes) in level.into_iter() {
+                push_match(&mut matches, end, codes);
+            }
+        }
+    }
+    return matches;
+}
+
+fn match_term(term: &Term, values: &[Option<&str>], pos: usize) -> Matches {
+    if pos >= values.len() {
+        return match *term {
+            Term::Group(ref group) => match_group(group, values, pos),
+            Term::Options(_) => vec![(pos, vec![])],
+            _ => vec![],
+        };
+    }
+    return match *term {
+        Term::Kind(ref code) => vec![(pos + 1, vec![code.clone()])],
+        Term::Keyword(ref keyword) => match values[pos] {
+            Some(value) if value == keyword => vec![(pos + 1, vec![Code::Literal])],
+            _ => vec![],
+        },
+        Term::Level => match values[pos] {
+            Some(value) if is_level(value) => vec![(pos + 1, vec![Code::Normal])],
+            _ => vec![],
+        },
+        Term::Group(ref group) => match_group(group, values, pos),
+        // Options are taken for as long as there are any, like Tcl does
+        Term::Options(ref options) => {
+            let mut p = pos;
+            let mut codes = vec![];
+            while p < values.len() {
+                let value = match values[p] {
+                    Some(value) => value,
+                    None => break,
+                };
+                if value == "--" {
+                    codes.push(Code::Literal);
+                    p += 1;
+                    break;
+                }
+                match options.iter().find(|(name, _)| name == value) {
+                    Some(&(_, None)) => {
+                        codes.push(Code::Literal);
+                        p += 1;
+                    },
+                    Some(&(_, Some(ref kind))) if p + 1 < values.len() => {
+                        codes.push(Code::Literal);
+                        codes.push(kind.clone());
+                        p += 2;
+                    },
+                    _ => break,
+                }
+            }
+            vec![(p, codes)]
+        },
+    };
+}
+
+// Whether uplevel would take `value` as a level rather than a script
+fn is_level(value: &str) -> bool {
+    let digits = value.strip_prefix("#").unwrap_or(value);
+    return digits.len() > 0 && digits.bytes().all(|b| b.is_ascii_digit());
+}
+
+/// A set of signatures, by command (or command and subcommand) name
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Signatures {
+    commands: HashMap<String, Signature>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignaturesFile {
+    signatures: HashMap<String, String>,
+}
+
+impl Signatures {
+    /// The signatures of builtin commands
+    pub fn builtin() -> &'static Signatures {
+        static BUILTIN: OnceLock<Signatures> = OnceLock::new();
+        return BUILTIN.get_or_init(|| {
+            Signatures::parse_toml(include_str!("signatures.toml")).expect("builtin signatures are invalid")
+        });
+    }
+
+    /// Reads signatures from a file like `signatures.toml`, with a
+    /// `[signatures]` table of command names and signatures
+    pub fn parse_toml(string: &str) -> Result<Signatures, String> {
+        let file: SignaturesFile = toml::from_str(string).map_err(|err| err.to_string())?;
+        let mut signatures = Signatures::default();
+        for (name, signature) in file.signatures.iter() {
+            signatures.insert(name, signature)?;
+        }
+        return Ok(signatures);
+    }
+
+    /// Adds (or replaces) the signature of a command
+    pub fn insert(&mut self, name: &str, signature: &str) -> Result<(), String> {
+        let parsed = Signature::parse(signature).map_err(|err| format!("{} (for {})", err, name))?;
+        self.commands.insert(name.to_owned(), parsed);
+        return Ok(());
+    }
+
+    /// Adds all the signatures in `other`, replacing any for the same commands
+    pub fn extend(&mut self, other: Signatures) {
+        self.commands.extend(other.commands.into_iter());
+    }
+
+    /// The signature for a command name, e.g. `eval`, or a command and
+    /// subcommand separated by a space, e.g. `namespace eval`
+    pub fn get(&self, name: &str) -> Option<&Signature> {
+        return self.commands.get(name);
+    }
+}
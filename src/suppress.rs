@@ -0,0 +1,122 @@
+//! Suppression comments, which acknowledge findings that have been reviewed:
+//!
+//! - `# tclscan: ignore <rule-id>[,<rule-id>...] [reason]`, on the line
+//!   before a command or trailing it (after a `;`), suppresses findings for
+//!   those rules anywhere in the command
+//! - `# tclscan: ignore-file [reason]`, in a comment outside any command,
+//!   suppresses all findings in the file
+
+use rstcl::{self, Position, Span};
+use rules;
+use parser;
+use CheckResult::{self, Warn};
+
+const PREFIX: &'static str = "tclscan:";
+
+pub struct Suppression<'a> {
+    /// The whole comment line the suppression is on
+    line: &'a str,
+    /// Where `line` starts in the script
+    offset: usize,
+    rules: Vec<&'a str>,
+    used: bool,
+}
+
+// The directive and its arguments in a comment line, if it's a suppression
+fn directive<'a>(line: &'a str) -> Option<(&'a str, &'a str)> {
+    let line = line.trim();
+    if !line.starts_with("#") {
+        return None;
+    }
+    let text = line[1..].trim_start();
+    if !text.starts_with(PREFIX) {
+        return None;
+    }
+    let text = text[PREFIX.len()..].trim_start();
+    return Some(match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim_start()),
+        None => (text, ""),
+    });
+}
+
+/// Where `sub`, a slice of `string`, starts in it
+fn offset_in(string: &str, sub: &str) -> usize {
+    return sub.as_ptr() as usize - string.as_ptr() as usize;
+}
+
+/// The `ignore` suppressions in `comment`, which precedes a command in
+/// `string`. The first is marked if it trails the command before, which ended
+/// at `prev_end` (if there is one).
+pub fn parse_comment<'a>(string: &'a str, comment: &'a str, prev_end: Option<usize>) -> Vec<(Suppression<'a>, bool)> {
+    let mut suppressions = vec![];
+    // No comment is represented by an empty string, which may not be in `string`
+    if comment.len() == 0 {
+        return suppressions;
+    }
+    let comment_start = offset_in(string, comment);
+    let trails = match prev_end {
+        Some(prev_end) => !string[prev_end..comment_start].contains('\n') &&
+            !string[..prev_end].ends_with('\n'),
+        None => false,
+    };
+    let mut first = true;
+    for line in comment.split('\n') {
+        if let Some(("ignore", args)) = directive(line) {
+            let rule_ids = args.split(char::is_whitespace).next().unwrap_or("");
+            suppressions.push((Suppression {
+                line: line.trim(),
+                offset: offset_in(string, line.trim_start()),
+                rules: rule_ids.split(',').filter(|id| id.len() > 0).collect(),
+                used: false,
+            }, first && trails));
+        }
+        first = false;
+    }
+    return suppressions;
+}
+
+/// Removes the findings which are suppressed, adding a finding for each
+/// suppression which didn't match any (see `rules::UNUSED_SUPPRESSION`).
+/// `string` is the script starting at `pos` which the suppressions are in.
+pub fn apply<'a>(results: Vec<CheckResult<'a>>, mut suppressions: Vec<Suppression<'a>>,
+                 string: &'a str, pos: Position) -> Vec<CheckResult<'a>> {
+    if suppressions.len() == 0 {
+        return results;
+    }
+    let mut kept = vec![];
+    for result in results.into_iter() {
+        let rule = result.rule();
+        match suppressions.iter_mut().find(|s| s.rules.contains(&rule.id)) {
+            Some(suppression) => suppression.used = true,
+            None => kept.push(result),
+        }
+    }
+    for suppression in suppressions.into_iter().filter(|s| !s.used) {
+        kept.push(unused(suppression.line, suppression.offset, string, pos));
+    }
+    return kept;
+}
+
+/// Whether an `ignore-file` directive is in a top-level comment of `string`,
+/// and if so the finding to report if it turns out to be unused
+pub fn ignore_file<'a>(string: &'a str) -> Option<CheckResult<'a>> {
+    let comments = rstcl::parse_script_recover_at(string, Position::start())
+        .filter_map(|parsed| parsed.ok().and_then(|parse| parse.comment));
+    for comment in comments.filter(|comment| comment.len() > 0) {
+        for line in comment.split('\n') {
+            if let Some(("ignore-file", _)) = directive(line) {
+                let line = line.trim();
+                return Some(unused(line, offset_in(string, line), string, Position::start()));
+            }
+        }
+    }
+    return None;
+}
+
+fn unused<'a>(line: &'a str, offset: usize, string: &'a str, pos: Position) -> CheckResult<'a> {
+    let span = Span {
+        start: parser::locate(string, pos, offset),
+        end: parser::locate(string, pos, offset + line.len()),
+    };
+    return Warn(line, &rules::UNUSED_SUPPRESSION, line, span);
+}
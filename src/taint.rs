@@ -0,0 +1,273 @@
+//! Tracks which variables only hold safe values (see `val_safety`) as a
+//! script runs, so e.g. `set n [llength $l]; expr $n+1` isn't reported.
+//!
+//! Variables start out unsafe, become safe when assigned from a safe value
+//! (e.g. a number, `[llength ...]` or `incr`) or checked with
+//! `string is integer`, and become unsafe again when anything else might
+//! change them. Scripts which may not run, or may run repeatedly, like the
+//! bodies of `if` and `while`, only keep variables safe if they stay safe
+//! through them.
+
+use std::collections::HashMap;
+use rstcl::{self, TclToken, TokenType};
+use {is_braced, literal_safety, val_safety, Code, Safety, ScanOptions};
+
+/// The variables known to only hold safe values at a point in a script, and
+/// where they're safe
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Vars {
+    safe: HashMap<String, Safety>,
+}
+
+impl Vars {
+    /// Where the value a `TokenType::Variable` token substitutes is safe
+    pub fn safety(&self, token: &TclToken) -> Safety {
+        assert!(token.ttype == TokenType::Variable);
+        return var_name(token).map_or(Safety::Unsafe, |name| self.name_safety(name));
+    }
+
+    /// Forgets everything, e.g. after code which could set any variable
+    pub fn clear(&mut self) {
+        self.safe.clear();
+    }
+
+    /// Where the value of a variable, by name, is safe
+    pub fn name_safety(&self, name: &str) -> Safety {
+        return self.safe.get(name).copied().unwrap_or(Safety::Unsafe);
+    }
+
+    /// Marks a variable as holding a number
+    pub fn insert(&mut self, name: &str) {
+        self.safe.insert(name.to_owned(), Safety::Script);
+    }
+
+    /// Only keeps the variables which are also safe in `other`, where they're
+    /// safe in both
+    pub fn intersect(&mut self, other: &Vars) {
+        self.safe.retain(|name, safety| match other.safe.get(name) {
+            Some(&other_safety) => {
+                *safety = (*safety).min(other_safety);
+                true
+            },
+            None => false,
+        });
+    }
+
+    /// Updates the variables a command sets
+    pub fn update(&mut self, tokens: &[TclToken], options: &ScanOptions) {
+        let name = tokens[0].literal();
+        let args = &tokens[1..];
+        match (name.as_deref(), args.len()) {
+            (Some("set"), 2) => self.assign(&args[0], val_safety(&args[1], options, self)),
+            (Some("set"), 1) => (),
+            // incr fails unless the variable ends up an integer
+            (Some("incr"), 1) | (Some("incr"), 2) => self.assign(&args[0], Safety::Script),
+            (Some("append"), n) | (Some("lappend"), n) if n > 0 => {
+                let mut safety = args[0].literal().and_then(|var| self.safe.get(&*var)).copied().unwrap_or(Safety::Unsafe);
+                for arg in args[1..].iter() {
+                    safety = safety.min(val_safety(arg, options, self));
+                }
+                // The elements of a list are separated by spaces
+                if name.as_deref() == Some("lappend") {
+                    safety = safety.min(Safety::Expr);
+                }
+                self.assign(&args[0], safety);
+            },
+            (Some("foreach"), n) | (Some("lmap"), n) if n >= 3 && n % 2 == 1 => {
+                for pair in args[..n-1].chunks(2) {
+                    let safety = element_safety(&pair[1], options, self);
+                    let names = pair[0].literal();
+                    match names.as_deref().map(rstcl::parse_list) {
+                        Some(Ok(elements)) => for element in elements.iter() {
+                            self.set(&element.value, safety);
+                        },
+                        _ => self.clear(),
+                    }
+                }
+            },
+            (Some("dict"), n) if n > 0 => match args[0].literal().as_deref() {
+                // The keys and values of the dictionary
+                Some("for") | Some("map") if n == 4 => {
+                    let safety = element_safety(&args[2], options, self);
+                    let names = args[1].literal();
+                    match names.as_deref().map(rstcl::parse_list) {
+                        Some(Ok(elements)) => for element in elements.iter() {
+                            self.set(&element.value, safety);
+                        },
+                        _ => self.clear(),
+                    }
+                },
+                // Could set a variable for any key
+                Some("with") => self.clear(),
+                _ => self.forget_args(args),
+            },
+            (Some("unset"), _) => self.forget_args(args),
+            // Anything else might set variables it's given the names of
+            (_, _) => self.forget_args(args),
+        }
+    }
+
+    fn assign(&mut self, var: &TclToken, safety: Safety) {
+        match var.literal() {
+            Some(name) => self.set(&name, safety),
+            // Could be any variable
+            None => self.clear(),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, safety: Safety) {
+        if safety == Safety::Unsafe {
+            self.safe.remove(name);
+        } else {
+            self.safe.insert(name.to_owned(), safety);
+        }
+    }
+
+    fn forget_args(&mut self, args: &[TclToken]) {
+        for arg in args.iter() {
+            if let Some(name) = arg.literal() {
+                self.safe.remove(&*name);
+            }
+        }
+    }
+}
+
+// Where each element of a list is safe. A literal list is split into its
+// elements, whereas a substituted one is as safe as the whole.
+fn element_safety(token: &TclToken, options: &ScanOptions, vars: &Vars) -> Safety {
+    let value = match token.literal() {
+        Some(value) => value,
+        None => return val_safety(token, options, vars),
+    };
+    return match rstcl::parse_list(&value) {
+        Ok(elements) => elements.iter().map(|element| literal_safety(&element.value)).min().unwrap_or(Safety::Script),
+        Err(_) => Safety::Unsafe,
+    };
+}
+
+// The name of a scalar variable, i.e. not an array element
+fn var_name<'a>(token: &TclToken<'a>) -> Option<&'a str> {
+    return match token.tokens[..] {
+        [ref name] if name.ttype == TokenType::Text => Some(name.val),
+        _ => None,
+    };
+}
+
+/// Whether a command's script arguments run in a scope of their own (or the
+/// caller's), rather than alongside the command
+pub fn is_new_scope(tokens: &[TclToken]) -> bool {
+    return match tokens[0].literal().as_deref() {
+        // Level 0 is the current scope
+        Some("uplevel") => match tokens.get(1).and_then(|token| token.literal()).as_deref() {
+            Some("0") | Some("#0") => tokens.len() < 3,
+            _ => true,
+        },
+        Some("proc") | Some("apply") | Some("namespace") | Some("interp") => true,
+        _ => false,
+    };
+}
+
+/// Whether a command sets variables before running its script arguments,
+/// e.g. the loop variables of `foreach`, rather than after
+pub fn sets_vars_first(tokens: &[TclToken]) -> bool {
+    let words: Vec<_> = tokens.iter().take(2).map(|token| token.literal()).collect();
+    let words: Vec<Option<&str>> = words.iter().map(|word| word.as_deref()).collect();
+    return match words[..] {
+        [Some("foreach"), _] | [Some("lmap"), _] => true,
+        [Some("dict"), Some("for")] | [Some("dict"), Some("map")] | [Some("dict"), Some("with")] => true,
+        _ => false,
+    };
+}
+
+/// The variables an `if` condition checks are numbers with `string is`,
+/// e.g. `{[string is integer -strict $n]}`, and whether the check is negated,
+/// e.g. `{![string is integer $n]}`
+pub fn guards(token: &TclToken) -> (Vec<String>, bool) {
+    let val = token.val;
+    if !is_braced(token) {
+        return (vec![], false);
+    }
+    let parse = match rstcl::parse_expr(&val[1..val.len()-1]) {
+        Ok((parse, _)) => parse,
+        Err(_) => return (vec![], false),
+    };
+    let mut names = vec![];
+    let negated = match parse.tokens[0].tokens[..] {
+        [ref not, ref operand] if not.ttype == TokenType::Operator && not.val == "!" => {
+            names.extend(operand_guard(operand).into_iter());
+            true
+        },
+        // Conditions joined with && all have to hold
+        [ref op, ref left, ref right] if op.ttype == TokenType::Operator && op.val == "&&" => {
+            names.extend(operand_guard(left).into_iter());
+            names.extend(operand_guard(right).into_iter());
+            false
+        },
+        _ => {
+            names.extend(operand_guard(&parse.tokens[0]).into_iter());
+            false
+        },
+    };
+    return (names, negated);
+}
+
+fn operand_guard(token: &TclToken) -> Option<String> {
+    let subst = match token.tokens[..] {
+        [ref subst] if subst.ttype == TokenType::Command => subst,
+        [ref subexpr] if subexpr.ttype == TokenType::SubExpr => return operand_guard(subexpr),
+        _ => return None,
+    };
+    let parse = match rstcl::parse_command(&subst.val[1..subst.val.len()-1]) {
+        Ok((parse, _)) => parse,
+        Err(_) => return None,
+    };
+    let words: Vec<Option<_>> = parse.tokens.iter().map(|token| token.literal()).collect();
+    let words: Vec<Option<&str>> = words.iter().map(|word| word.as_deref()).collect();
+    let var = match words[..] {
+        [Some("string"), Some("is"), Some(class), ref rest @ ..] if is_numeric_class(class) && rest.len() > 0 => {
+            let options_safe = rest[..rest.len()-1].iter().all(|word| *word == Some("-strict"));
+            if !options_safe {
+                return None;
+            }
+            &parse.tokens[parse.tokens.len()-1]
+        },
+        _ => return None,
+    };
+    return match var.tokens[..] {
+        [ref var] if var.ttype == TokenType::Variable => var_name(var).map(|name| name.to_owned()),
+        _ => None,
+    };
+}
+
+fn is_numeric_class(class: &str) -> bool {
+    return match class {
+        "integer" | "wideinteger" | "entier" | "digit" | "double" | "boolean" => true,
+        _ => false,
+    };
+}
+
+/// Whether a braced script always ends by leaving its caller, e.g. with
+/// `return` or `error`
+pub fn exits(token: &TclToken) -> bool {
+    let val = token.val;
+    if !is_braced(token) {
+        return false;
+    }
+    let last = rstcl::iter_script(&val[1..val.len()-1])
+        .filter_map(|parse| parse.ok())
+        .filter(|parse| parse.tokens.len() > 0)
+        .last();
+    return match last.and_then(|parse| parse.tokens[0].literal().map(|name| name.into_owned())).as_deref() {
+        Some("return") | Some("error") | Some("throw") | Some("exit") | Some("break") | Some("continue") => true,
+        _ => false,
+    };
+}
+
+/// Whether an argument of this kind is a script run alongside the command,
+/// which can change its variables
+pub fn is_body(code: &Code) -> bool {
+    return match *code {
+        Code::Block | Code::PatternBodies => true,
+        _ => false,
+    };
+}
@@ -0,0 +1,161 @@
+//! Walks the tree built by `ast`, calling hooks on a `Visitor` as each node
+//! is entered and exited.
+
+use ast::{Body, Command, Expr, Script, Subst, Word};
+use rstcl::{TclToken, TokenType};
+
+/// A node in the tree, as seen in the path of ancestors given to a visitor
+#[derive(Clone, Copy, Debug)]
+pub enum Node<'r, 'a: 'r> {
+    Script(&'r Script<'a>),
+    Command(&'r Command<'a>),
+    Word(&'r Word<'a>),
+    Expr(&'r Expr<'a>),
+    /// A token within a word or expr, e.g. a variable or a subexpression
+    Token(&'r TclToken<'a>),
+}
+
+/// Hooks called by `walk_script`. Each is given the path of ancestors of the
+/// node, outermost first. All do nothing by default.
+///
+/// ```
+/// use tclscan::ast::parse_script;
+/// use tclscan::rstcl::TclToken;
+/// use tclscan::visit::{walk_script,Node,Visitor};
+/// // Finds variables used in the bodies of procs
+/// struct ProcVars<'a> { vars: Vec<&'a str> }
+/// impl<'r, 'a: 'r> Visitor<'r, 'a> for ProcVars<'a> {
+///     fn enter_variable(&mut self, token: &'r TclToken<'a>, path: &[Node<'r, 'a>]) {
+///         let in_proc = path.iter().any(|node| match *node {
+///             Node::Command(cmd) => cmd.words[0].token.val == "proc",
+///             _ => false,
+///         });
+///         if in_proc {
+///             self.vars.push(token.val);
+///         }
+///     }
+/// }
+/// let script = parse_script("puts $a\nproc p {} {\n  while {$b} { puts [x $c] }\n  subst {$d}\n}");
+/// let mut visitor = ProcVars { vars: vec![] };
+/// walk_script(&mut visitor, &script);
+/// assert!(visitor.vars == vec!["$b", "$c", "$d"]);
+/// ```
+pub trait Visitor<'r, 'a: 'r> {
+    fn enter_script(&mut self, _script: &'r Script<'a>, _path: &[Node<'r, 'a>]) {}
+    fn exit_script(&mut self, _script: &'r Script<'a>, _path: &[Node<'r, 'a>]) {}
+    fn enter_command(&mut self, _command: &'r Command<'a>, _path: &[Node<'r, 'a>]) {}
+    fn exit_command(&mut self, _command: &'r Command<'a>, _path: &[Node<'r, 'a>]) {}
+    fn enter_word(&mut self, _word: &'r Word<'a>, _path: &[Node<'r, 'a>]) {}
+    fn exit_word(&mut self, _word: &'r Word<'a>, _path: &[Node<'r, 'a>]) {}
+    /// A `TokenType::Variable` token, in a word or an expr
+    fn enter_variable(&mut self, _token: &'r TclToken<'a>, _path: &[Node<'r, 'a>]) {}
+    fn exit_variable(&mut self, _token: &'r TclToken<'a>, _path: &[Node<'r, 'a>]) {}
+    /// A `TokenType::Command` token, in a word or an expr. Its script is
+    /// walked between the two hooks.
+    fn enter_command_subst(&mut self, _token: &'r TclToken<'a>, _path: &[Node<'r, 'a>]) {}
+    fn exit_command_subst(&mut self, _token: &'r TclToken<'a>, _path: &[Node<'r, 'a>]) {}
+    /// A braced expr argument of a command (e.g. the condition of `while`)
+    fn enter_expr(&mut self, _expr: &'r Expr<'a>, _path: &[Node<'r, 'a>]) {}
+    fn exit_expr(&mut self, _expr: &'r Expr<'a>, _path: &[Node<'r, 'a>]) {}
+    /// A `TokenType::SubExpr` token within an expr
+    fn enter_subexpr(&mut self, _token: &'r TclToken<'a>, _path: &[Node<'r, 'a>]) {}
+    fn exit_subexpr(&mut self, _token: &'r TclToken<'a>, _path: &[Node<'r, 'a>]) {}
+}
+
+/// Walks a script depth first, in source order
+pub fn walk_script<'r, 'a: 'r, V: Visitor<'r, 'a>>(visitor: &mut V, script: &'r Script<'a>) {
+    let mut walker = Walker { visitor: visitor, path: vec![] };
+    walker.script(script);
+}
+
+struct Walker<'v, 'r, 'a: 'r, V: Visitor<'r, 'a> + 'v> {
+    visitor: &'v mut V,
+    path: Vec<Node<'r, 'a>>,
+}
+
+impl<'v, 'r, 'a: 'r, V: Visitor<'r, 'a> + 'v> Walker<'v, 'r, 'a, V> {
+    fn script(&mut self, script: &'r Script<'a>) {
+        self.visitor.enter_script(script, &self.path);
+        self.path.push(Node::Script(script));
+        for command in script.commands.iter() {
+            self.command(command);
+        }
+        self.path.pop();
+        self.visitor.exit_script(script, &self.path);
+    }
+
+    fn command(&mut self, command: &'r Command<'a>) {
+        self.visitor.enter_command(command, &self.path);
+        self.path.push(Node::Command(command));
+        for word in command.words.iter() {
+            self.word(word);
+        }
+        self.path.pop();
+        self.visitor.exit_command(command, &self.path);
+    }
+
+    fn word(&mut self, word: &'r Word<'a>) {
+        self.visitor.enter_word(word, &self.path);
+        self.path.push(Node::Word(word));
+        let mut substs = word.substs.iter();
+        for token in word.token.tokens.iter() {
+            self.token(token, &mut substs);
+        }
+        match word.body {
+            Some(Body::Script(ref script)) => self.script(script),
+            Some(Body::Expr(ref expr)) => self.expr(expr),
+            Some(Body::Scripts(ref scripts)) => for script in scripts.iter() {
+                self.script(script);
+            },
+            Some(Body::Subst(ref subst)) => self.subst(subst),
+            Some(Body::Error(_)) | None => (),
+        }
+        self.path.pop();
+        self.visitor.exit_word(word, &self.path);
+    }
+
+    fn expr(&mut self, expr: &'r Expr<'a>) {
+        self.visitor.enter_expr(expr, &self.path);
+        self.path.push(Node::Expr(expr));
+        let mut substs = expr.substs.iter();
+        for token in expr.parse.tokens.iter() {
+            self.token(token, &mut substs);
+        }
+        self.path.pop();
+        self.visitor.exit_expr(expr, &self.path);
+    }
+
+    // The tokens of a subst argument are visited as part of its word
+    fn subst(&mut self, subst: &'r Subst<'a>) {
+        let mut substs = subst.substs.iter();
+        for token in subst.parse.tokens.iter() {
+            self.token(token, &mut substs);
+        }
+    }
+
+    // Command substitutions were parsed in the order they're found here, so
+    // each one takes the next script from `substs`
+    fn token<I: Iterator<Item=&'r Script<'a>>>(&mut self, token: &'r TclToken<'a>, substs: &mut I) {
+        match token.ttype {
+            TokenType::Variable => self.visitor.enter_variable(token, &self.path),
+            TokenType::Command => self.visitor.enter_command_subst(token, &self.path),
+            TokenType::SubExpr => self.visitor.enter_subexpr(token, &self.path),
+            _ => (),
+        }
+        self.path.push(Node::Token(token));
+        if token.ttype == TokenType::Command {
+            let script = substs.next().expect("command substitution wasn't parsed");
+            self.script(script);
+        }
+        for subtoken in token.tokens.iter() {
+            self.token(subtoken, substs);
+        }
+        self.path.pop();
+        match token.ttype {
+            TokenType::Variable => self.visitor.exit_variable(token, &self.path),
+            TokenType::Command => self.visitor.exit_command_subst(token, &self.path),
+            TokenType::SubExpr => self.visitor.exit_subexpr(token, &self.path),
+            _ => (),
+        }
+    }
+}